@@ -0,0 +1,435 @@
+use crate::{docker::Docker, format::CodeStr, podman::Podman};
+use byte_unit::Byte;
+use std::{collections::HashMap, io, path::PathBuf, str::FromStr, time::Duration};
+
+// An image known to the container runtime
+#[derive(Clone, Debug)]
+pub struct Image {
+    pub id: String,
+    pub repo_tags: Vec<String>,
+    pub labels: HashMap<String, String>,
+}
+
+// A layer of an image. Layers are identified by their content and the content of all the layers
+// below them, so two images share a layer on disk if and only if they share a layer ID.
+#[derive(Clone, Debug)]
+pub struct Layer {
+    pub id: String,
+    pub size: Byte,
+}
+
+// The space used by an image
+#[derive(Clone, Copy, Debug)]
+pub struct ImageUsage {
+    // The total size of the image's layers
+    pub size: Byte,
+
+    // The portion of `size` which is due to layers shared with other images
+    pub shared_size: Byte,
+}
+
+impl ImageUsage {
+    // The space which would be freed by deleting this image and no others
+    pub fn unique_size(&self) -> Byte {
+        Byte::from_bytes(
+            self.size
+                .get_bytes()
+                .saturating_sub(self.shared_size.get_bytes()),
+        )
+    }
+}
+
+// An entry in the build cache
+#[derive(Clone, Debug)]
+pub struct BuildCacheEntry {
+    pub id: String,
+    pub size: Byte,
+
+    // When the entry was last used as a duration since the UNIX epoch, if it has been used at all
+    pub last_used: Option<Duration>,
+
+    // Whether the entry is being used by a build right now
+    pub in_use: bool,
+}
+
+// A volume known to the container runtime
+#[derive(Clone, Debug)]
+pub struct Volume {
+    pub name: String,
+    pub size: Byte,
+
+    // The number of containers using the volume
+    pub containers: u64,
+
+    // Whether the volume was created implicitly for a container rather than by name
+    pub anonymous: bool,
+}
+
+impl Volume {
+    // Anonymous volumes are named with 64 random hexadecimal digits.
+    pub fn has_anonymous_name(name: &str) -> bool {
+        name.len() == 64 && name.chars().all(|c| c.is_ascii_hexdigit())
+    }
+}
+
+// The space used by images, the build cache, and volumes
+#[derive(Clone, Debug)]
+pub struct DiskUsage {
+    // The total space used by all images, counting shared layers once
+    pub total: Byte,
+
+    // The space used by each image, keyed by ID
+    pub images: HashMap<String, ImageUsage>,
+
+    // The entries in the build cache, if the container runtime has one
+    pub build_cache: Vec<BuildCacheEntry>,
+
+    // The volumes
+    pub volumes: Vec<Volume>,
+}
+
+impl DiskUsage {
+    // The total space used by the build cache
+    pub fn build_cache_total(&self) -> Byte {
+        Byte::from_bytes(
+            self.build_cache
+                .iter()
+                .map(|entry| entry.size.get_bytes())
+                .sum(),
+        )
+    }
+}
+
+// A container known to the container runtime
+#[derive(Clone, Debug)]
+pub struct Container {
+    pub id: String,
+    pub image_id: String,
+    pub labels: HashMap<String, String>,
+
+    // The state of the container, e.g., `running` or `exited`
+    pub state: String,
+}
+
+impl Container {
+    // Determine whether the container is currently running, which counts as using its image.
+    pub fn running(&self) -> bool {
+        self.state == "running" || self.state == "paused" || self.state == "restarting"
+    }
+
+    // Determine whether the container has stopped after running.
+    pub fn exited(&self) -> bool {
+        self.state == "exited" || self.state == "dead"
+    }
+}
+
+// An event from the container runtime, normalized to use Docker's vocabulary
+#[derive(Clone, Debug)]
+pub struct Event {
+    pub r#type: String,
+    pub action: String,
+    pub actor_id: String,
+    pub attributes: HashMap<String, String>,
+}
+
+// The operations Docuum needs from a container runtime
+pub trait Backend {
+    // A human-readable name for the container runtime, for logging
+    fn name(&self) -> &'static str;
+
+    // Look up an image by name or ID.
+    fn inspect_image(&self, image: &str) -> io::Result<Image>;
+
+    // List all the images, including intermediate images.
+    fn images(&self) -> io::Result<Vec<Image>>;
+
+    // Determine the layers of an image, from the bottom up.
+    fn image_layers(&self, image_id: &str) -> io::Result<Vec<Layer>>;
+
+    // List all the containers, including stopped ones.
+    fn containers(&self) -> io::Result<Vec<Container>>;
+
+    // Get the space used by images, both in total and for each image.
+    fn disk_usage(&self) -> io::Result<DiskUsage>;
+
+    // Get the directory in which the container runtime stores its data.
+    fn root_dir(&self) -> io::Result<PathBuf>;
+
+    // Delete an image, even if it has multiple tags.
+    fn delete_image(&self, image_id: &str) -> io::Result<()>;
+
+    // Delete an entry from the build cache.
+    fn delete_build_cache(&self, id: &str) -> io::Result<()>;
+
+    // Determine when a container stopped as a duration since the UNIX epoch, if it has. Fails with
+    // `io::ErrorKind::NotFound` if the container no longer exists.
+    fn container_finished_at(&self, container_id: &str) -> io::Result<Option<Duration>>;
+
+    // Delete a stopped container.
+    fn delete_container(&self, container_id: &str) -> io::Result<()>;
+
+    // Delete a volume which isn't used by any containers.
+    fn delete_volume(&self, name: &str) -> io::Result<()>;
+
+    // Subscribe to the event stream. The iterator only ends if the stream is interrupted.
+    fn events(&self) -> io::Result<Box<dyn Iterator<Item = io::Result<Event>> + Send>>;
+
+    // Ask the container runtime for the ID of an image.
+    fn image_id(&self, image: &str) -> io::Result<String> {
+        self.inspect_image(image).map(|image| image.id)
+    }
+}
+
+// The supported container runtimes
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BackendKind {
+    Docker,
+    Podman,
+}
+
+impl BackendKind {
+    // The names accepted by `from_str`, for the command-line interface
+    pub const NAMES: &'static [&'static str] = &["docker", "podman"];
+
+    // Connect to the container runtime.
+    pub fn connect(self) -> io::Result<Box<dyn Backend>> {
+        Ok(match self {
+            Self::Docker => Box::new(Docker::from_env()?),
+            Self::Podman => Box::new(Podman::from_env()?),
+        })
+    }
+}
+
+impl FromStr for BackendKind {
+    type Err = io::Error;
+
+    fn from_str(name: &str) -> io::Result<Self> {
+        match name {
+            "docker" => Ok(Self::Docker),
+            "podman" => Ok(Self::Podman),
+            _ => Err(io::Error::other(format!(
+                "Unknown backend {}.",
+                name.code_str(),
+            ))),
+        }
+    }
+}
+
+// An in-memory container runtime for testing the logic which drives a real one
+#[cfg(test)]
+pub mod fake {
+    use super::{Backend, Container, DiskUsage, Event, Image, ImageUsage, Layer, Volume};
+    use byte_unit::Byte;
+    use std::{
+        cell::RefCell,
+        collections::{HashMap, HashSet},
+        env, io, iter,
+        path::PathBuf,
+        time::Duration,
+    };
+
+    pub struct FakeBackend {
+        // The images and their sizes in bytes. Images don't share any layers.
+        images: RefCell<Vec<(Image, u128)>>,
+
+        containers: RefCell<Vec<Container>>,
+
+        volumes: RefCell<Vec<Volume>>,
+
+        // When each stopped container stopped
+        pub finish_times: HashMap<String, Duration>,
+
+        // Images which fail to be deleted, e.g., because a container started using them
+        pub undeletable: HashSet<String>,
+
+        // The root directory of the container runtime
+        pub root_dir: PathBuf,
+
+        // The images and volumes which were deleted, in order
+        deleted: RefCell<Vec<String>>,
+    }
+
+    impl FakeBackend {
+        pub fn new(images: &[(&str, u128)]) -> Self {
+            Self {
+                images: RefCell::new(images.iter().map(|&(id, size)| (image(id), size)).collect()),
+                containers: RefCell::new(vec![]),
+                volumes: RefCell::new(vec![]),
+                finish_times: HashMap::new(),
+                undeletable: HashSet::new(),
+                root_dir: env::temp_dir(),
+                deleted: RefCell::new(vec![]),
+            }
+        }
+
+        // Add a container using an image.
+        pub fn add_container(&self, id: &str, image_id: &str, state: &str) {
+            self.containers.borrow_mut().push(Container {
+                id: id.to_owned(),
+                image_id: image_id.to_owned(),
+                labels: HashMap::new(),
+                state: state.to_owned(),
+            });
+        }
+
+        // Add a volume of the given size in bytes, used by the given number of containers. Volumes
+        // with anonymous names are anonymous.
+        pub fn add_volume(&self, name: &str, size: u128, containers: u64) {
+            self.volumes.borrow_mut().push(Volume {
+                name: name.to_owned(),
+                size: Byte::from_bytes(size),
+                containers,
+                anonymous: Volume::has_anonymous_name(name),
+            });
+        }
+
+        // The images and volumes which were deleted, in order
+        pub fn deleted(&self) -> Vec<String> {
+            self.deleted.borrow().clone()
+        }
+    }
+
+    // Construct an image without tags or labels.
+    pub fn image(id: &str) -> Image {
+        Image {
+            id: id.to_owned(),
+            repo_tags: vec![],
+            labels: HashMap::new(),
+        }
+    }
+
+    // Construct the error the container runtime reports for something which doesn't exist.
+    fn not_found(what: &str) -> io::Error {
+        io::Error::new(io::ErrorKind::NotFound, format!("No such {}.", what))
+    }
+
+    impl Backend for FakeBackend {
+        fn name(&self) -> &'static str {
+            "Fake"
+        }
+
+        fn inspect_image(&self, image: &str) -> io::Result<Image> {
+            self.images
+                .borrow()
+                .iter()
+                .find(|(candidate, _)| {
+                    candidate.id == image || candidate.repo_tags.iter().any(|tag| tag == image)
+                })
+                .map(|(image, _)| image.clone())
+                .ok_or_else(|| not_found("image"))
+        }
+
+        fn images(&self) -> io::Result<Vec<Image>> {
+            Ok(self
+                .images
+                .borrow()
+                .iter()
+                .map(|(image, _)| image.clone())
+                .collect())
+        }
+
+        fn image_layers(&self, image_id: &str) -> io::Result<Vec<Layer>> {
+            self.images
+                .borrow()
+                .iter()
+                .find(|(image, _)| image.id == image_id)
+                .map(|&(_, size)| {
+                    vec![Layer {
+                        id: image_id.to_owned(),
+                        size: Byte::from_bytes(size),
+                    }]
+                })
+                .ok_or_else(|| not_found("image"))
+        }
+
+        fn containers(&self) -> io::Result<Vec<Container>> {
+            Ok(self.containers.borrow().clone())
+        }
+
+        fn disk_usage(&self) -> io::Result<DiskUsage> {
+            let images = self.images.borrow();
+            Ok(DiskUsage {
+                total: Byte::from_bytes(images.iter().map(|&(_, size)| size).sum()),
+                images: images
+                    .iter()
+                    .map(|(image, size)| {
+                        (
+                            image.id.clone(),
+                            ImageUsage {
+                                size: Byte::from_bytes(*size),
+                                shared_size: Byte::from_bytes(0),
+                            },
+                        )
+                    })
+                    .collect(),
+                build_cache: vec![],
+                volumes: self.volumes.borrow().clone(),
+            })
+        }
+
+        fn root_dir(&self) -> io::Result<PathBuf> {
+            Ok(self.root_dir.clone())
+        }
+
+        // Images used by running containers are deleted anyway, as a forced deletion would, so it's
+        // up to the caller to leave them alone.
+        fn delete_image(&self, image_id: &str) -> io::Result<()> {
+            if self.undeletable.contains(image_id) {
+                return Err(io::Error::other(format!(
+                    "Image {} can't be deleted.",
+                    image_id,
+                )));
+            }
+
+            let mut images = self.images.borrow_mut();
+            let count = images.len();
+            images.retain(|(image, _)| image.id != image_id);
+            if images.len() == count {
+                return Err(not_found("image"));
+            }
+
+            self.deleted.borrow_mut().push(image_id.to_owned());
+            Ok(())
+        }
+
+        fn delete_build_cache(&self, _id: &str) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn container_finished_at(&self, container_id: &str) -> io::Result<Option<Duration>> {
+            if self
+                .containers
+                .borrow()
+                .iter()
+                .any(|container| container.id == container_id)
+            {
+                Ok(self.finish_times.get(container_id).copied())
+            } else {
+                Err(not_found("container"))
+            }
+        }
+
+        fn delete_container(&self, container_id: &str) -> io::Result<()> {
+            self.containers
+                .borrow_mut()
+                .retain(|container| container.id != container_id);
+            Ok(())
+        }
+
+        fn delete_volume(&self, name: &str) -> io::Result<()> {
+            let mut volumes = self.volumes.borrow_mut();
+            let count = volumes.len();
+            volumes.retain(|volume| volume.name != name);
+            if volumes.len() == count {
+                return Err(not_found("volume"));
+            }
+
+            self.deleted.borrow_mut().push(name.to_owned());
+            Ok(())
+        }
+
+        fn events(&self) -> io::Result<Box<dyn Iterator<Item = io::Result<Event>> + Send>> {
+            Ok(Box::new(iter::empty()))
+        }
+    }
+}
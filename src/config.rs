@@ -0,0 +1,272 @@
+use crate::{
+    backend::BackendKind, duration, format::CodeStr, keep::KeepRule, state::StateFormat,
+    threshold::Threshold,
+};
+use log::LevelFilter;
+use serde::{de::Error, Deserialize, Deserializer};
+use serde_yaml::{Mapping, Value};
+use std::{
+    fmt::Display,
+    fs::read_to_string,
+    io,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+// Where to look for the configuration file if one isn't given explicitly, in order of preference
+fn default_paths() -> Vec<PathBuf> {
+    let mut paths = vec![];
+
+    if let Some(path) = dirs::config_dir() {
+        paths.push(path.join("docuum/config.yml"));
+    }
+
+    if cfg!(unix) {
+        paths.push(PathBuf::from("/etc/docuum.yml"));
+    }
+
+    paths
+}
+
+// The contents of a configuration file. Every setting is optional, and command-line options take
+// precedence over the corresponding settings here.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    #[serde(default, deserialize_with = "deserialize_parsed")]
+    pub threshold: Option<Threshold>,
+
+    #[serde(default, deserialize_with = "deserialize_parsed")]
+    pub low_watermark: Option<Threshold>,
+
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub max_idle: Option<Duration>,
+
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub interval: Option<Duration>,
+
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub debounce: Option<Duration>,
+
+    #[serde(default, deserialize_with = "deserialize_parsed")]
+    pub volume_threshold: Option<Threshold>,
+
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub volume_max_idle: Option<Duration>,
+
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub remove_exited_after: Option<Duration>,
+
+    #[serde(default, deserialize_with = "deserialize_parsed")]
+    pub backend: Option<BackendKind>,
+
+    #[serde(default)]
+    pub dry_run: Option<bool>,
+
+    #[serde(default)]
+    pub build_cache: Option<bool>,
+
+    #[serde(default, deserialize_with = "deserialize_keep")]
+    pub keep: Option<Vec<KeepRule>>,
+
+    #[serde(default, deserialize_with = "deserialize_keep_labels")]
+    pub keep_labels: Option<Vec<KeepRule>>,
+
+    #[serde(default, deserialize_with = "deserialize_parsed")]
+    pub log_level: Option<LevelFilter>,
+
+    #[serde(default)]
+    pub state_path: Option<PathBuf>,
+
+    #[serde(default, deserialize_with = "deserialize_parsed")]
+    pub state_format: Option<StateFormat>,
+}
+
+// Deserialize a string with `FromStr`.
+fn deserialize_parsed<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|value| T::from_str(&value).map_err(D::Error::custom))
+        .transpose()
+}
+
+// Deserialize a duration such as `7d`.
+fn deserialize_duration<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Duration>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+        .map(|duration| duration::parse(&duration).map_err(D::Error::custom))
+        .transpose()
+}
+
+// Deserialize a list of keep patterns.
+fn deserialize_keep<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Vec<KeepRule>>, D::Error> {
+    Option::<Vec<String>>::deserialize(deserializer)?
+        .map(|patterns| {
+            patterns
+                .iter()
+                .map(|pattern| KeepRule::from_str(pattern).map_err(D::Error::custom))
+                .collect()
+        })
+        .transpose()
+}
+
+// Deserialize a list of keep label rules.
+fn deserialize_keep_labels<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Vec<KeepRule>>, D::Error> {
+    Option::<Vec<String>>::deserialize(deserializer)?
+        .map(|rules| {
+            rules
+                .iter()
+                .map(|rule| KeepRule::label(rule).map_err(D::Error::custom))
+                .collect()
+        })
+        .transpose()
+}
+
+// Find the line on which a top-level key appears, counting from 1.
+fn key_line(yaml: &str, key: &str) -> Option<usize> {
+    let spellings = [key.to_owned(), format!("\"{}\"", key), format!("'{}'", key)];
+    yaml.lines()
+        .position(|line| {
+            spellings.iter().any(|spelling| {
+                line.strip_prefix(spelling.as_str())
+                    .is_some_and(|rest| rest.trim_start().starts_with(':'))
+            })
+        })
+        .map(|index| index + 1)
+}
+
+// Settings are validated after they're read, so errors about invalid values don't say where the
+// value came from. To find out, we validate each top-level setting on its own. Returns a description
+// of the first invalid setting along with the position of its key, if that can be determined.
+fn locate_error(yaml: &str) -> Option<String> {
+    let Ok(Value::Mapping(mapping)) = serde_yaml::from_str::<Value>(yaml) else {
+        return None;
+    };
+
+    mapping.into_iter().find_map(|(key, value)| {
+        let line = key_line(yaml, key.as_str()?)?;
+        let mut setting = Mapping::new();
+        setting.insert(key, value);
+        serde_yaml::from_value::<Config>(Value::Mapping(setting))
+            .err()
+            .map(|error| format!("{} at line {} column 1", error, line))
+    })
+}
+
+// Load the configuration file. If no path is given, look in the default locations.
+pub fn load(path: Option<&Path>) -> io::Result<Config> {
+    // Find the file.
+    let path = if let Some(path) = path {
+        path.to_owned()
+    } else if let Some(path) = default_paths().into_iter().find(|path| path.exists()) {
+        path
+    } else {
+        debug!("No configuration file found.");
+        return Ok(Config::default());
+    };
+
+    // Log what we are trying to do in case an error occurs.
+    debug!(
+        "Loading the configuration from {}\u{2026}",
+        path.to_string_lossy().code_str(),
+    );
+
+    // Read the YAML from disk.
+    let yaml = read_to_string(&path).map_err(|error| {
+        io::Error::other(format!(
+            "Unable to read configuration file {}. Details: {}",
+            path.to_string_lossy().code_str(),
+            error,
+        ))
+    })?;
+
+    // Deserialize the YAML. An empty file is a valid configuration with nothing set.
+    if yaml.trim().is_empty() {
+        return Ok(Config::default());
+    }
+
+    serde_yaml::from_str(&yaml).map_err(|error| {
+        io::Error::other(format!(
+            "Invalid configuration file {}. Details: {}",
+            path.to_string_lossy().code_str(),
+            locate_error(&yaml).unwrap_or_else(|| error.to_string()),
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{key_line, load, Config};
+    use crate::{fixtures::TempDir, threshold::Threshold};
+    use byte_unit::Byte;
+    use std::{fs::write, io, time::Duration};
+
+    // Load a configuration file with the given contents.
+    fn load_yaml(yaml: &str) -> io::Result<Config> {
+        let directory = TempDir::new();
+        let path = directory.path().join("config.yml");
+        write(&path, yaml).unwrap();
+        load(Some(&path))
+    }
+
+    #[test]
+    fn key_line_finds_top_level_keys() {
+        let yaml = "threshold: 10 GB\n\n\"max-idle\": 7d\nkeep:\n  - threshold: x\n";
+
+        assert_eq!(key_line(yaml, "threshold"), Some(1));
+        assert_eq!(key_line(yaml, "max-idle"), Some(3));
+        assert_eq!(key_line(yaml, "keep"), Some(4));
+        assert_eq!(key_line(yaml, "interval"), None);
+    }
+
+    #[test]
+    fn load_reads_a_valid_file() {
+        let config = load_yaml(
+            "threshold: 10 GB\n\
+             max-idle: 7d\n\
+             dry-run: true\n\
+             keep:\n  \
+               - ci/base:*\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.threshold,
+            Some(Threshold::Absolute(Byte::from_bytes(10_000_000_000))),
+        );
+        assert_eq!(config.max_idle, Some(Duration::from_secs(7 * 24 * 60 * 60)));
+        assert_eq!(config.dry_run, Some(true));
+        assert_eq!(config.keep.map(|keep| keep.len()), Some(1));
+        assert_eq!(config.interval, None);
+    }
+
+    #[test]
+    fn load_reports_the_line_of_an_unknown_key() {
+        let error = load_yaml("threshold: 10 GB\nthreshhold: 20 GB\n")
+            .unwrap_err()
+            .to_string();
+
+        assert!(error.contains("threshhold"), "{}", error);
+        assert!(error.contains("line 2"), "{}", error);
+    }
+
+    #[test]
+    fn load_reports_the_line_of_an_invalid_value() {
+        let error = load_yaml("dry-run: true\n\nthreshold: lots\n")
+            .unwrap_err()
+            .to_string();
+
+        assert!(error.contains("Invalid threshold"), "{}", error);
+        assert!(error.contains("line 3"), "{}", error);
+    }
+}
@@ -0,0 +1,203 @@
+use crate::{
+    backend::{Backend, Container},
+    format::{timestamp, CodeStr},
+    labels::KEEP_LABEL,
+    Settings,
+};
+use std::{collections::HashMap, io, time::Duration};
+
+// Determine whether a container is exempt from removal by its labels.
+fn exempt(container: &Container, settings: &Settings) -> bool {
+    container
+        .labels
+        .get(KEEP_LABEL)
+        .is_some_and(|keep| keep.trim().eq_ignore_ascii_case("true"))
+        || settings
+            .keep
+            .iter()
+            .any(|rule| rule.matches_labels(&container.labels))
+}
+
+// Determine when each container which isn't running stopped, as a duration since the UNIX epoch.
+// Containers which have never run are left out, as are containers which were removed in the
+// meantime (e.g., by `docker run --rm`), since they no longer pin anything. This inspects each
+// container, so it's done once per vacuum and the result is shared.
+pub fn finish_times(
+    backend: &dyn Backend,
+    containers: &[Container],
+) -> io::Result<HashMap<String, Duration>> {
+    let mut finish_times = HashMap::new();
+
+    for container in containers.iter().filter(|container| !container.running()) {
+        match backend.container_finished_at(&container.id) {
+            Ok(Some(finished_at)) => {
+                finish_times.insert(container.id.clone(), finished_at);
+            }
+            Ok(None) => {}
+            Err(error) if error.kind() == io::ErrorKind::NotFound => debug!("{}", error),
+            Err(error) => return Err(error),
+        }
+    }
+
+    Ok(finish_times)
+}
+
+// If configured, remove containers which exited long enough ago so they no longer pin their images.
+// Returns the containers which remain (in dry-run mode, those which wouldn't have been removed) and
+// when the next container will have been exited for too long, if ever, as a duration since the UNIX
+// epoch. `finish_times` tells when each container stopped (see `finish_times`).
+pub fn remove_exited(
+    backend: &dyn Backend,
+    settings: &Settings,
+    containers: Vec<Container>,
+    finish_times: &HashMap<String, Duration>,
+    now: Duration,
+) -> (Vec<Container>, Option<Duration>) {
+    // Stop here unless containers should be removed.
+    let Some(remove_exited_after) = settings.remove_exited_after else {
+        return (containers, None);
+    };
+
+    let mut remaining = vec![];
+    let mut next_removal: Option<Duration> = None;
+
+    for container in containers {
+        if !container.exited() || exempt(&container, settings) {
+            remaining.push(container);
+            continue;
+        }
+
+        // Containers which don't report when they stopped are left alone.
+        let Some(&finished_at) = finish_times.get(&container.id) else {
+            remaining.push(container);
+            continue;
+        };
+
+        let deadline = finished_at + remove_exited_after;
+        if deadline > now {
+            next_removal = Some(next_removal.map_or(deadline, |next| next.min(deadline)));
+            remaining.push(container);
+            continue;
+        }
+
+        if settings.dry_run {
+            info!(
+                "Would remove container {}, which exited at {}.",
+                container.id.code_str(),
+                timestamp(finished_at).code_str(),
+            );
+        } else {
+            info!(
+                "Removing container {}, which exited at {}\u{2026}",
+                container.id.code_str(),
+                timestamp(finished_at).code_str(),
+            );
+
+            // Keep going if the container can't be removed, since it still pins its image.
+            if let Err(error) = backend.delete_container(&container.id) {
+                error!("{}", error);
+                remaining.push(container);
+            }
+        }
+    }
+
+    if let Some(next_removal) = next_removal {
+        debug!(
+            "The next container will have been exited for too long at {}.",
+            timestamp(next_removal).code_str(),
+        );
+    }
+
+    (remaining, next_removal)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{finish_times, remove_exited};
+    use crate::{
+        backend::{fake::FakeBackend, Backend, Container},
+        fixtures::settings,
+    };
+    use std::{collections::HashMap, time::Duration};
+
+    fn ids(containers: &[Container]) -> Vec<&str> {
+        containers
+            .iter()
+            .map(|container| container.id.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn containers_exited_for_too_long_are_removed() {
+        let mut backend = FakeBackend::new(&[("a", 100)]);
+        backend.add_container("old", "a", "exited");
+        backend.add_container("recent", "a", "exited");
+        backend.add_container("running", "a", "running");
+        backend
+            .finish_times
+            .insert("old".to_owned(), Duration::from_secs(10));
+        backend
+            .finish_times
+            .insert("recent".to_owned(), Duration::from_secs(95));
+        let mut settings = settings(0);
+        settings.remove_exited_after = Some(Duration::from_secs(50));
+
+        let containers = backend.containers().unwrap();
+        let finish_times = finish_times(&backend, &containers).unwrap();
+        assert_eq!(finish_times.len(), 2);
+
+        let (remaining, next_removal) = remove_exited(
+            &backend,
+            &settings,
+            containers,
+            &finish_times,
+            Duration::from_secs(100),
+        );
+        assert_eq!(ids(&remaining), vec!["recent", "running"]);
+        assert_eq!(next_removal, Some(Duration::from_secs(145)));
+        assert_eq!(
+            ids(&backend.containers().unwrap()),
+            vec!["recent", "running"]
+        );
+    }
+
+    #[test]
+    fn containers_are_kept_unless_configured() {
+        let backend = FakeBackend::new(&[("a", 100)]);
+        backend.add_container("old", "a", "exited");
+        let mut finish_times = HashMap::new();
+        finish_times.insert("old".to_owned(), Duration::from_secs(10));
+
+        let (remaining, next_removal) = remove_exited(
+            &backend,
+            &settings(0),
+            backend.containers().unwrap(),
+            &finish_times,
+            Duration::from_secs(100),
+        );
+        assert_eq!(ids(&remaining), vec!["old"]);
+        assert_eq!(next_removal, None);
+    }
+
+    #[test]
+    fn vanished_containers_are_skipped() {
+        let mut backend = FakeBackend::new(&[("a", 100)]);
+        backend.add_container("stopped", "a", "exited");
+        backend
+            .finish_times
+            .insert("stopped".to_owned(), Duration::from_secs(10));
+
+        // The container list is out of date by the time each container is inspected.
+        let mut containers = backend.containers().unwrap();
+        containers.push(Container {
+            id: "vanished".to_owned(),
+            image_id: "a".to_owned(),
+            labels: HashMap::new(),
+            state: "exited".to_owned(),
+        });
+
+        let finish_times = finish_times(&backend, &containers).unwrap();
+        assert_eq!(finish_times.len(), 1);
+        assert_eq!(finish_times.get("stopped"), Some(&Duration::from_secs(10)),);
+    }
+}
@@ -0,0 +1,796 @@
+use crate::{
+    backend::{
+        Backend, BuildCacheEntry, Container, DiskUsage, Event, Image, ImageUsage, Layer, Volume,
+    },
+    format::CodeStr,
+    http::{encode, Client},
+};
+use byte_unit::Byte;
+use chrono::DateTime;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    convert::TryFrom,
+    env,
+    hash::{Hash, Hasher},
+    io::{self, BufRead},
+    path::PathBuf,
+    time::Duration,
+};
+
+// The label Docker puts on anonymous volumes
+const ANONYMOUS_VOLUME_LABEL: &str = "com.docker.volume.anonymous";
+
+// The environment variable which tells us how to reach the Docker daemon
+const DOCKER_HOST_ENV: &str = "DOCKER_HOST";
+
+// Where the Docker daemon listens if `DOCKER_HOST` is not set
+const DEFAULT_DOCKER_HOST: &str = "unix:///var/run/docker.sock";
+
+// A Docker event (a message from the `/events` endpoint)
+#[derive(Deserialize, Serialize, Debug)]
+pub struct EventRecord {
+    #[serde(rename = "Type")]
+    pub r#type: String,
+
+    #[serde(rename = "Action")]
+    pub action: String,
+
+    #[serde(rename = "Actor")]
+    pub actor: EventActor,
+}
+
+// A Docker event actor
+#[derive(Deserialize, Serialize, Debug)]
+pub struct EventActor {
+    #[serde(rename = "ID")]
+    pub id: String,
+
+    #[serde(rename = "Attributes", default)]
+    pub attributes: HashMap<String, String>,
+}
+
+impl From<EventRecord> for Event {
+    fn from(event_record: EventRecord) -> Self {
+        Self {
+            r#type: event_record.r#type,
+            action: event_record.action,
+            actor_id: event_record.actor.id,
+            attributes: event_record.actor.attributes,
+        }
+    }
+}
+
+// An image as reported by the `/images/{name}/json` and `/images/json` endpoints
+#[derive(Deserialize, Serialize, Debug)]
+pub struct ImageRecord {
+    #[serde(rename = "Id")]
+    pub id: String,
+
+    #[serde(rename = "RepoTags", default)]
+    pub repo_tags: Option<Vec<String>>,
+
+    // Only reported by the `/images/json` endpoint
+    #[serde(rename = "Labels", default)]
+    pub labels: Option<HashMap<String, String>>,
+
+    // Only reported by the `/images/{name}/json` endpoint
+    #[serde(rename = "Config", default)]
+    pub config: Option<ImageConfigRecord>,
+
+    // Only reported by the `/images/{name}/json` endpoint
+    #[serde(rename = "RootFS", default)]
+    pub root_fs: Option<RootFsRecord>,
+}
+
+// The configuration of an image
+#[derive(Deserialize, Serialize, Debug)]
+pub struct ImageConfigRecord {
+    #[serde(rename = "Labels", default)]
+    pub labels: Option<HashMap<String, String>>,
+}
+
+// The root filesystem of an image
+#[derive(Deserialize, Serialize, Debug)]
+pub struct RootFsRecord {
+    // The diff IDs of the layers, from the bottom up
+    #[serde(rename = "Layers", default)]
+    pub layers: Vec<String>,
+}
+
+// An entry in the history of an image, as reported by the `/images/{name}/history` endpoint
+#[derive(Deserialize, Serialize, Debug)]
+pub struct HistoryRecord {
+    #[serde(rename = "Size")]
+    pub size: i64,
+}
+
+impl From<ImageRecord> for Image {
+    fn from(image_record: ImageRecord) -> Self {
+        let config_labels = image_record.config.and_then(|config| config.labels);
+        Self {
+            id: image_record.id,
+            // Untagged images are reported with a tag of `<none>:<none>` by some versions of Docker.
+            repo_tags: image_record
+                .repo_tags
+                .unwrap_or_default()
+                .into_iter()
+                .filter(|repo_tag| repo_tag != "<none>:<none>")
+                .collect(),
+            labels: image_record.labels.or(config_labels).unwrap_or_default(),
+        }
+    }
+}
+
+// A container as reported by the `/containers/json` endpoint
+#[derive(Deserialize, Serialize, Debug)]
+pub struct ContainerRecord {
+    #[serde(rename = "Id")]
+    pub id: String,
+
+    #[serde(rename = "ImageID")]
+    pub image_id: String,
+
+    #[serde(rename = "Labels", default)]
+    pub labels: Option<HashMap<String, String>>,
+
+    #[serde(rename = "State", default)]
+    pub state: String,
+}
+
+// The state of a container as reported by the `/containers/{id}/json` endpoint
+#[derive(Deserialize, Serialize, Debug)]
+pub struct ContainerStateRecord {
+    #[serde(rename = "FinishedAt", default)]
+    pub finished_at: Option<String>,
+}
+
+// A container as reported by the `/containers/{id}/json` endpoint
+#[derive(Deserialize, Serialize, Debug)]
+pub struct ContainerInspectRecord {
+    #[serde(rename = "State")]
+    pub state: ContainerStateRecord,
+}
+
+impl From<ContainerRecord> for Container {
+    fn from(container_record: ContainerRecord) -> Self {
+        Self {
+            id: container_record.id,
+            image_id: container_record.image_id,
+            labels: container_record.labels.unwrap_or_default(),
+            state: container_record.state,
+        }
+    }
+}
+
+// An image as reported by the `/system/df` endpoint
+#[derive(Deserialize, Serialize, Debug)]
+struct ImageSpaceRecord {
+    #[serde(rename = "Id")]
+    pub id: String,
+
+    #[serde(rename = "Size")]
+    pub size: i64,
+
+    #[serde(rename = "SharedSize")]
+    pub shared_size: i64,
+}
+
+// A build cache entry as reported by the `/system/df` endpoint
+#[derive(Deserialize, Serialize, Debug)]
+struct BuildCacheRecord {
+    #[serde(rename = "ID")]
+    pub id: String,
+
+    #[serde(rename = "Size")]
+    pub size: i64,
+
+    #[serde(rename = "LastUsedAt", default)]
+    pub last_used_at: Option<String>,
+
+    #[serde(rename = "InUse", default)]
+    pub in_use: bool,
+}
+
+impl From<BuildCacheRecord> for BuildCacheEntry {
+    fn from(build_cache_record: BuildCacheRecord) -> Self {
+        Self {
+            id: build_cache_record.id,
+            size: bytes(build_cache_record.size),
+            last_used: build_cache_record
+                .last_used_at
+                .as_deref()
+                .and_then(parse_timestamp),
+            in_use: build_cache_record.in_use,
+        }
+    }
+}
+
+// The space used by a volume as reported by the `/system/df` endpoint
+#[derive(Deserialize, Serialize, Debug)]
+struct VolumeUsageRecord {
+    #[serde(rename = "Size")]
+    pub size: i64,
+
+    #[serde(rename = "RefCount")]
+    pub ref_count: i64,
+}
+
+// A volume as reported by the `/system/df` endpoint
+#[derive(Deserialize, Serialize, Debug)]
+struct VolumeSpaceRecord {
+    #[serde(rename = "Name")]
+    pub name: String,
+
+    #[serde(rename = "Labels", default)]
+    pub labels: Option<HashMap<String, String>>,
+
+    #[serde(rename = "UsageData", default)]
+    pub usage_data: Option<VolumeUsageRecord>,
+}
+
+impl From<VolumeSpaceRecord> for Volume {
+    fn from(volume_space_record: VolumeSpaceRecord) -> Self {
+        let anonymous = volume_space_record
+            .labels
+            .is_some_and(|labels| labels.contains_key(ANONYMOUS_VOLUME_LABEL))
+            || Self::has_anonymous_name(&volume_space_record.name);
+        let (size, ref_count) = volume_space_record
+            .usage_data
+            .map_or((-1, -1), |usage_data| {
+                (usage_data.size, usage_data.ref_count)
+            });
+
+        Self {
+            name: volume_space_record.name,
+            size: bytes(size),
+            // Docker reports a negative count or no usage data at all if it doesn't know, so we
+            // assume the volume is used.
+            containers: u64::try_from(ref_count).unwrap_or(1),
+            anonymous,
+        }
+    }
+}
+
+// The response from the `/system/df` endpoint
+#[derive(Deserialize, Serialize, Debug)]
+struct SpaceRecord {
+    #[serde(rename = "LayersSize")]
+    pub layers_size: i64,
+
+    #[serde(rename = "Images", default)]
+    pub images: Option<Vec<ImageSpaceRecord>>,
+
+    #[serde(rename = "BuildCache", default)]
+    pub build_cache: Option<Vec<BuildCacheRecord>>,
+
+    #[serde(rename = "Volumes", default)]
+    pub volumes: Option<Vec<VolumeSpaceRecord>>,
+}
+
+impl From<SpaceRecord> for DiskUsage {
+    fn from(space_record: SpaceRecord) -> Self {
+        Self {
+            total: bytes(space_record.layers_size),
+            images: space_record
+                .images
+                .unwrap_or_default()
+                .into_iter()
+                .map(|image_space_record| {
+                    (
+                        image_space_record.id,
+                        ImageUsage {
+                            size: bytes(image_space_record.size),
+                            shared_size: bytes(image_space_record.shared_size),
+                        },
+                    )
+                })
+                .collect(),
+            build_cache: space_record
+                .build_cache
+                .unwrap_or_default()
+                .into_iter()
+                .map(BuildCacheEntry::from)
+                .collect(),
+            volumes: space_record
+                .volumes
+                .unwrap_or_default()
+                .into_iter()
+                .map(Volume::from)
+                .collect(),
+        }
+    }
+}
+
+// The response from the `/build/prune` endpoint
+#[derive(Deserialize, Serialize, Debug)]
+struct PruneRecord {
+    #[serde(rename = "CachesDeleted", default)]
+    pub caches_deleted: Option<Vec<String>>,
+}
+
+// The response from the `/info` endpoint
+#[derive(Deserialize, Serialize, Debug)]
+struct InfoRecord {
+    #[serde(rename = "DockerRootDir")]
+    pub docker_root_dir: String,
+}
+
+// Escape a string for use in a regular expression. Escaping a punctuation character is always safe,
+// even if it has no special meaning.
+fn escape_regex(input: &str) -> String {
+    let mut output = String::new();
+    for character in input.chars() {
+        if character.is_ascii_punctuation() {
+            output.push('\\');
+        }
+        output.push(character);
+    }
+
+    output
+}
+
+// Convert a size reported by the Engine API into a `Byte`. Negative sizes mean "unknown".
+pub fn bytes(size: i64) -> Byte {
+    Byte::from_bytes(u128::try_from(size).unwrap_or(0))
+}
+
+// Parse a timestamp reported by the Engine API as a duration since the UNIX epoch. Docker reports
+// the zero time (in the year 1) for events which haven't happened, so we ignore times before the
+// epoch.
+pub fn parse_timestamp(timestamp: &str) -> Option<Duration> {
+    DateTime::parse_from_rfc3339(timestamp)
+        .ok()
+        .and_then(|time| {
+            u64::try_from(time.timestamp())
+                .ok()
+                .map(|seconds| Duration::new(seconds, time.timestamp_subsec_nanos()))
+        })
+}
+
+// Determine when a container stopped, given the prefix for the API endpoints.
+pub fn container_finished_at(
+    client: &Client,
+    prefix: &str,
+    container_id: &str,
+) -> io::Result<Option<Duration>> {
+    client
+        .request_json::<ContainerInspectRecord>(
+            "GET",
+            &format!("{}/containers/{}/json", prefix, encode(container_id)),
+        )
+        .map(|container_inspect_record| {
+            container_inspect_record
+                .state
+                .finished_at
+                .as_deref()
+                .and_then(parse_timestamp)
+        })
+        .map_err(|error| {
+            io::Error::new(
+                error.kind(),
+                format!(
+                    "Unable to determine when container {} stopped. Details: {}",
+                    container_id.code_str(),
+                    error,
+                ),
+            )
+        })
+}
+
+// Determine the layers of an image, given the prefix for the API endpoints. The layers themselves
+// are only reported by their diff IDs, so we identify them by hashing the diff IDs of each layer and
+// the layers below it (like a chain ID). Their sizes come from the history of the image, which has
+// an entry for each layer and for each step that didn't create a layer. The latter have a size of
+// zero, so we can match up the layers with the nonempty history entries unless some layers are also
+// empty. In that case, we give up rather than guess.
+pub fn image_layers(client: &Client, prefix: &str, image_id: &str) -> io::Result<Vec<Layer>> {
+    let diff_ids = client
+        .request_json::<ImageRecord>(
+            "GET",
+            &format!("{}/images/{}/json", prefix, encode(image_id)),
+        )?
+        .root_fs
+        .map(|root_fs| root_fs.layers)
+        .unwrap_or_default();
+
+    // The history is reported from the top down.
+    let sizes = client
+        .request_json::<Vec<HistoryRecord>>(
+            "GET",
+            &format!("{}/images/{}/history", prefix, encode(image_id)),
+        )?
+        .into_iter()
+        .rev()
+        .filter(|history_record| history_record.size > 0)
+        .map(|history_record| bytes(history_record.size))
+        .collect::<Vec<_>>();
+
+    if sizes.len() != diff_ids.len() {
+        return Err(io::Error::other(format!(
+            "Unable to match the layers of image {} with its history.",
+            image_id.code_str(),
+        )));
+    }
+
+    let mut hasher = DefaultHasher::new();
+    Ok(diff_ids
+        .iter()
+        .zip(sizes)
+        .map(|(diff_id, size)| {
+            diff_id.hash(&mut hasher);
+            Layer {
+                id: format!("{:016x}", hasher.finish()),
+                size,
+            }
+        })
+        .collect())
+}
+
+// Parse the event stream, which consists of one JSON object per line.
+pub fn parse_events<R: BufRead>(reader: R) -> impl Iterator<Item = io::Result<EventRecord>> {
+    reader.lines().filter_map(|line_option| {
+        // Unwrap the line.
+        let line = match line_option {
+            Ok(line) => line,
+            Err(error) => return Some(Err(error)),
+        };
+        debug!("Incoming event: {}", line.code_str());
+
+        // Parse the line as an event.
+        match serde_json::from_str::<EventRecord>(&line) {
+            Ok(event_record) => {
+                debug!("Parsed as: {}", format!("{:?}", event_record).code_str());
+                Some(Ok(event_record))
+            }
+            Err(error) => {
+                debug!("Skipping due to: {}", error);
+                None
+            }
+        }
+    })
+}
+
+// The Docker daemon, accessed via the Docker Engine API
+pub struct Docker {
+    client: Client,
+}
+
+impl Docker {
+    // Connect to the daemon specified by `DOCKER_HOST`, if it's set, or to the default Unix socket
+    // otherwise.
+    pub fn from_env() -> io::Result<Self> {
+        let host = env::var(DOCKER_HOST_ENV)
+            .ok()
+            .filter(|host| !host.is_empty())
+            .unwrap_or_else(|| DEFAULT_DOCKER_HOST.to_owned());
+
+        Ok(Self {
+            client: Client::new(&host)?,
+        })
+    }
+}
+
+impl Backend for Docker {
+    fn name(&self) -> &'static str {
+        "Docker"
+    }
+
+    fn inspect_image(&self, image: &str) -> io::Result<Image> {
+        // Query Docker for the image.
+        self.client
+            .request_json::<ImageRecord>("GET", &format!("/images/{}/json", encode(image)))
+            .map(Image::from)
+            .map_err(|error| {
+                io::Error::other(format!(
+                    "Unable to determine ID of image {}. Details: {}",
+                    image.code_str(),
+                    error,
+                ))
+            })
+    }
+
+    fn images(&self) -> io::Result<Vec<Image>> {
+        // Query Docker for the images.
+        self.client
+            .request_json::<Vec<ImageRecord>>("GET", "/images/json?all=1")
+            .map(|image_records| image_records.into_iter().map(Image::from).collect())
+            .map_err(|error| {
+                io::Error::other(format!(
+                    "Unable to determine IDs of all images. Details: {}",
+                    error,
+                ))
+            })
+    }
+
+    fn image_layers(&self, image_id: &str) -> io::Result<Vec<Layer>> {
+        image_layers(&self.client, "", image_id)
+    }
+
+    fn containers(&self) -> io::Result<Vec<Container>> {
+        // Query Docker for the containers. Docker reports the ID of the image each container was
+        // created from, so we don't need to resolve image names ourselves.
+        self.client
+            .request_json::<Vec<ContainerRecord>>("GET", "/containers/json?all=1")
+            .map(|container_records| container_records.into_iter().map(Container::from).collect())
+            .map_err(|error| {
+                io::Error::other(format!(
+                    "Unable to determine IDs of images currently in use by containers. Details: {}",
+                    error,
+                ))
+            })
+    }
+
+    fn disk_usage(&self) -> io::Result<DiskUsage> {
+        // Query Docker for the space usage.
+        self.client
+            .request_json::<SpaceRecord>("GET", "/system/df")
+            .map(DiskUsage::from)
+            .map_err(|error| {
+                io::Error::other(format!(
+                    "Unable to determine the disk space used by Docker images. Details: {}",
+                    error,
+                ))
+            })
+    }
+
+    fn root_dir(&self) -> io::Result<PathBuf> {
+        // Ask Docker where it keeps its data.
+        self.client
+            .request_json::<InfoRecord>("GET", "/info")
+            .map(|info_record| PathBuf::from(info_record.docker_root_dir))
+            .map_err(|error| {
+                io::Error::other(format!(
+                    "Unable to determine the Docker root directory. Details: {}",
+                    error,
+                ))
+            })
+    }
+
+    fn delete_image(&self, image_id: &str) -> io::Result<()> {
+        // Tell Docker to delete the image.
+        self.client
+            .request_empty(
+                "DELETE",
+                &format!("/images/{}?force=1&noprune=1", encode(image_id)),
+            )
+            .map_err(|error| {
+                io::Error::other(format!(
+                    "Unable to delete image {}. Details: {}",
+                    image_id.code_str(),
+                    error,
+                ))
+            })
+    }
+
+    fn delete_build_cache(&self, id: &str) -> io::Result<()> {
+        // Tell Docker to prune exactly this entry. The filter only accepts one ID, which it matches
+        // as a regular expression, so we anchor it. Without `all`, Docker would skip entries which
+        // aren't internal.
+        let filters = serde_json::json!({ "id": [format!("^{}$", escape_regex(id))] }).to_string();
+        let caches_deleted = self
+            .client
+            .request_json::<PruneRecord>(
+                "POST",
+                &format!("/build/prune?all=true&filters={}", encode(&filters)),
+            )
+            .map_err(|error| {
+                io::Error::other(format!(
+                    "Unable to delete build cache entry {}. Details: {}",
+                    id.code_str(),
+                    error,
+                ))
+            })?
+            .caches_deleted
+            .unwrap_or_default();
+
+        // Make sure Docker deleted what we asked for and nothing else.
+        for other_id in caches_deleted.iter().filter(|other_id| *other_id != id) {
+            warn!(
+                "Docker also deleted build cache entry {}.",
+                other_id.code_str(),
+            );
+        }
+        if caches_deleted.iter().any(|deleted_id| deleted_id == id) {
+            Ok(())
+        } else {
+            Err(io::Error::other(format!(
+                "Docker didn't delete build cache entry {}.",
+                id.code_str(),
+            )))
+        }
+    }
+
+    fn container_finished_at(&self, container_id: &str) -> io::Result<Option<Duration>> {
+        container_finished_at(&self.client, "", container_id)
+    }
+
+    fn delete_container(&self, container_id: &str) -> io::Result<()> {
+        // Tell Docker to delete the container. Its volumes are left alone.
+        self.client
+            .request_empty("DELETE", &format!("/containers/{}", encode(container_id)))
+            .map_err(|error| {
+                io::Error::other(format!(
+                    "Unable to delete container {}. Details: {}",
+                    container_id.code_str(),
+                    error,
+                ))
+            })
+    }
+
+    fn delete_volume(&self, name: &str) -> io::Result<()> {
+        // Tell Docker to delete the volume.
+        self.client
+            .request_empty("DELETE", &format!("/volumes/{}", encode(name)))
+            .map_err(|error| {
+                io::Error::other(format!(
+                    "Unable to delete volume {}. Details: {}",
+                    name.code_str(),
+                    error,
+                ))
+            })
+    }
+
+    fn events(&self) -> io::Result<Box<dyn Iterator<Item = io::Result<Event>> + Send>> {
+        // Subscribe to the event stream.
+        let response = self.client.request("GET", "/events")?;
+
+        Ok(Box::new(
+            parse_events(response.body).map(|event_record| event_record.map(Event::from)),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_events, VolumeSpaceRecord};
+    use crate::backend::Volume;
+    use byte_unit::Byte;
+    #[cfg(unix)]
+    use {
+        super::image_layers,
+        crate::{
+            backend::Layer,
+            fixtures::{json_response, serve},
+        },
+        std::io,
+    };
+
+    // The layers of an image with the given diff IDs and history, as captured from Docker
+    #[cfg(unix)]
+    fn layers(diff_ids: &[&str], history: &str) -> io::Result<Vec<Layer>> {
+        let inspect = format!(
+            r#"{{"Id":"sha256:3f57d9401f8d","RepoTags":["app:1"],"Config":{{"Labels":null}},"RootFS":{{"Type":"layers","Layers":{}}}}}"#,
+            serde_json::to_string(diff_ids).unwrap(),
+        );
+        let (client, handle) = serve(&[&json_response(&inspect), &json_response(history)]);
+
+        let layers = image_layers(&client, "", "sha256:3f57d9401f8d");
+        assert_eq!(
+            handle.join().unwrap(),
+            vec![
+                "GET /images/sha256:3f57d9401f8d/json HTTP/1.1",
+                "GET /images/sha256:3f57d9401f8d/history HTTP/1.1",
+            ],
+        );
+        layers
+    }
+
+    // The history of an image with a base layer of 5000 bytes and a layer of 2000 bytes on top
+    const HISTORY: &str = r#"[
+        {"Id":"sha256:3f57d9401f8d","Created":1700000300,"CreatedBy":"/bin/sh -c #(nop)  CMD [\"app\"]","Tags":["app:1"],"Size":0,"Comment":""},
+        {"Id":"<missing>","Created":1700000200,"CreatedBy":"/bin/sh -c apt-get install -y curl","Tags":null,"Size":2000,"Comment":""},
+        {"Id":"<missing>","Created":1700000100,"CreatedBy":"/bin/sh -c #(nop)  ENV PATH=/bin","Tags":null,"Size":0,"Comment":""},
+        {"Id":"<missing>","Created":1700000000,"CreatedBy":"/bin/sh -c #(nop) ADD file:b2e1 in / ","Tags":null,"Size":5000,"Comment":""}
+    ]"#;
+
+    fn convert_volume(json: &str) -> Volume {
+        Volume::from(serde_json::from_str::<VolumeSpaceRecord>(json).unwrap())
+    }
+
+    #[test]
+    fn volume_with_usage_data() {
+        let volume = convert_volume(
+            r#"{
+                "Name": "data",
+                "Labels": {},
+                "UsageData": {"Size": 1000, "RefCount": 0}
+            }"#,
+        );
+
+        assert_eq!(volume.name, "data");
+        assert_eq!(volume.size, Byte::from_bytes(1000));
+        assert_eq!(volume.containers, 0);
+        assert!(!volume.anonymous);
+    }
+
+    #[test]
+    fn volume_with_unknown_usage_is_in_use() {
+        let volume =
+            convert_volume(r#"{"Name": "data", "UsageData": {"Size": -1, "RefCount": -1}}"#);
+        assert_eq!(volume.containers, 1);
+
+        let volume = convert_volume(r#"{"Name": "data", "Labels": null}"#);
+        assert_eq!(volume.size, Byte::from_bytes(0));
+        assert_eq!(volume.containers, 1);
+    }
+
+    #[test]
+    fn anonymous_volumes_are_recognized_by_label_or_name() {
+        assert!(
+            convert_volume(r#"{"Name": "data", "Labels": {"com.docker.volume.anonymous": ""}}"#)
+                .anonymous
+        );
+        assert!(
+            convert_volume(&format!(
+                r#"{{"Name": "{}"}}"#,
+                "0123456789abcdef".repeat(4)
+            ))
+            .anonymous
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn image_layers_match_nonempty_history_entries() {
+        let layers = layers(&["sha256:base", "sha256:curl"], HISTORY).unwrap();
+
+        assert_eq!(
+            layers
+                .iter()
+                .map(|layer| layer.size.get_bytes())
+                .collect::<Vec<_>>(),
+            vec![5000, 2000],
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn image_layers_are_identified_by_the_layers_below_them() {
+        let layers_a = layers(&["sha256:base", "sha256:curl"], HISTORY).unwrap();
+        let layers_b = layers(&["sha256:base", "sha256:wget"], HISTORY).unwrap();
+        let layers_c = layers(&["sha256:other", "sha256:curl"], HISTORY).unwrap();
+
+        // Images with the same base share its layer.
+        assert_eq!(layers_a[0].id, layers_b[0].id);
+        assert_ne!(layers_a[1].id, layers_b[1].id);
+
+        // The same diff on top of a different base is a different layer.
+        assert_ne!(layers_a[0].id, layers_c[0].id);
+        assert_ne!(layers_a[1].id, layers_c[1].id);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn image_layers_which_do_not_match_the_history_are_an_error() {
+        assert!(layers(&["sha256:base"], HISTORY).is_err());
+        assert!(layers(&["sha256:base", "sha256:empty", "sha256:curl"], HISTORY).is_err());
+    }
+
+    #[test]
+    fn parse_events_skips_unrecognized_lines() {
+        let stream = concat!(
+            r#"{"status":"start","id":"5d7f","from":"app:1","Type":"container","Action":"start","Actor":{"ID":"5d7f","Attributes":{"image":"app:1","name":"web"}},"scope":"local","time":1700000000,"timeNano":1700000000000000000}"#,
+            "\n",
+            "not json\n",
+            r#"{"Type":"image","Action":"pull","Actor":{"ID":"app:1"},"scope":"local","time":1700000001}"#,
+            "\n",
+        );
+
+        let events = parse_events(stream.as_bytes())
+            .map(Result::unwrap)
+            .map(|event| (event.r#type, event.action, event.actor.id))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            events,
+            vec![
+                (
+                    "container".to_owned(),
+                    "start".to_owned(),
+                    "5d7f".to_owned()
+                ),
+                ("image".to_owned(), "pull".to_owned(), "app:1".to_owned()),
+            ],
+        );
+    }
+}
@@ -0,0 +1,143 @@
+use crate::format::CodeStr;
+use std::{fmt::Write, io, time::Duration};
+
+// The supported units, from largest to smallest, with their lengths in seconds
+const UNITS: &[(&str, u64)] = &[
+    ("w", 7 * 24 * 60 * 60),
+    ("d", 24 * 60 * 60),
+    ("h", 60 * 60),
+    ("m", 60),
+    ("s", 1),
+];
+
+// Parse a duration such as `90s`, `15m`, `12h`, `7d`, `2w`, or a combination such as `1d12h`.
+pub fn parse(input: &str) -> io::Result<Duration> {
+    let invalid = || {
+        io::Error::other(format!(
+            "Invalid duration {}. Expected a number followed by one of {}, e.g., {}.",
+            input.code_str(),
+            "s, m, h, d, w".code_str(),
+            "7d".code_str(),
+        ))
+    };
+
+    let mut seconds = 0_u64;
+    let mut rest = input.trim();
+    if rest.is_empty() {
+        return Err(invalid());
+    }
+
+    while !rest.is_empty() {
+        // Read the number.
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let number = rest[..digits].parse::<u64>().map_err(|_| invalid())?;
+        rest = rest[digits..].trim_start();
+
+        // Read the unit.
+        let (unit, length) = UNITS
+            .iter()
+            .find(|(unit, _)| rest.starts_with(unit))
+            .ok_or_else(invalid)?;
+        rest = rest[unit.len()..].trim_start();
+
+        seconds = number
+            .checked_mul(*length)
+            .and_then(|product| seconds.checked_add(product))
+            .ok_or_else(invalid)?;
+    }
+
+    Ok(Duration::from_secs(seconds))
+}
+
+// Format a duration using the largest units which fit, e.g., `1d12h`.
+pub fn format(duration: Duration) -> String {
+    let mut seconds = duration.as_secs();
+    if seconds == 0 {
+        return "0s".to_owned();
+    }
+
+    let mut output = String::new();
+    for (unit, length) in UNITS {
+        if seconds >= *length {
+            // The `unwrap` is safe because writing to a `String` never fails.
+            write!(output, "{}{}", seconds / length, unit).unwrap();
+            seconds %= length;
+        }
+    }
+
+    output
+}
+
+// Format a duration using only the largest unit which fits, rounding down, e.g., `1d` for 36 hours.
+pub fn format_approximate(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    UNITS
+        .iter()
+        .find(|(_, length)| seconds >= *length)
+        .map_or_else(
+            || "0s".to_owned(),
+            |(unit, length)| format!("{}{}", seconds / length, unit),
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format, parse};
+    use std::time::Duration;
+
+    #[test]
+    fn parse_single_units() {
+        assert_eq!(parse("90s").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse("15m").unwrap(), Duration::from_secs(15 * 60));
+        assert_eq!(parse("12h").unwrap(), Duration::from_secs(12 * 60 * 60));
+        assert_eq!(parse("7d").unwrap(), Duration::from_secs(7 * 24 * 60 * 60));
+        assert_eq!(parse("2w").unwrap(), Duration::from_secs(14 * 24 * 60 * 60));
+        assert_eq!(parse("0s").unwrap(), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn parse_combinations_and_whitespace() {
+        assert_eq!(parse("1d12h").unwrap(), Duration::from_secs(36 * 60 * 60));
+        assert_eq!(parse(" 1h 30m ").unwrap(), Duration::from_secs(90 * 60));
+        assert_eq!(parse("1m1m").unwrap(), Duration::from_secs(120));
+    }
+
+    #[test]
+    fn parse_rejects_invalid_durations() {
+        for input in &[
+            "",
+            "  ",
+            "7",
+            "d",
+            "7x",
+            "-1d",
+            "1.5h",
+            "1d 2",
+            "99999999999999999999s",
+        ] {
+            assert!(parse(input).is_err(), "{:?} should be invalid", input);
+        }
+        assert!(parse(&format!("{}w", u64::MAX)).is_err());
+    }
+
+    #[test]
+    fn format_uses_largest_units() {
+        assert_eq!(format(Duration::from_secs(0)), "0s");
+        assert_eq!(format(Duration::from_secs(59)), "59s");
+        assert_eq!(format(Duration::from_secs(36 * 60 * 60)), "1d12h");
+        assert_eq!(
+            format(Duration::from_secs(8 * 24 * 60 * 60 + 61)),
+            "1w1d1m1s"
+        );
+    }
+
+    #[test]
+    fn format_round_trips() {
+        for seconds in &[1, 60, 3601, 86_400, 694_861] {
+            let duration = Duration::from_secs(*seconds);
+            assert_eq!(parse(&format(duration)).unwrap(), duration);
+        }
+    }
+}
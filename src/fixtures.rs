@@ -0,0 +1,151 @@
+use crate::{
+    backend::BackendKind,
+    state::{self, ImageRecord, State, StateFormat},
+    threshold::Threshold,
+    Settings,
+};
+use byte_unit::Byte;
+use std::{
+    env,
+    fs::{create_dir_all, remove_dir_all},
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+#[cfg(unix)]
+use {
+    crate::http::Client,
+    std::{
+        io::{BufRead, BufReader, Write},
+        os::unix::net::UnixListener,
+        thread::{self, JoinHandle},
+    },
+};
+
+// Distinguishes the directories of tests which run at the same time
+static DIRECTORY_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+// A directory which is deleted when the test is done
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new() -> Self {
+        let path = env::temp_dir().join(format!(
+            "docuum-test-{}-{}",
+            process::id(),
+            DIRECTORY_COUNTER.fetch_add(1, Ordering::SeqCst),
+        ));
+        create_dir_all(&path).unwrap();
+        Self(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = remove_dir_all(&self.0);
+    }
+}
+
+// A record of an image which was first and last used the given number of seconds after the UNIX
+// epoch
+pub fn record(last_used: u64) -> ImageRecord {
+    ImageRecord::new(Duration::from_secs(last_used))
+}
+
+// A state in which each image and volume was last used the given number of seconds after the UNIX
+// epoch
+pub fn state(images: &[(&str, u64)], volumes: &[(&str, u64)]) -> State {
+    let mut state = state::initial();
+    for &(image_id, last_used) in images {
+        state.images.insert(image_id.to_owned(), record(last_used));
+    }
+    for &(name, last_used) in volumes {
+        state
+            .volumes
+            .insert(name.to_owned(), Duration::from_secs(last_used));
+    }
+
+    state
+}
+
+// Settings with the given threshold in bytes and nothing else turned on
+pub fn settings(threshold: u128) -> Settings {
+    Settings {
+        threshold: Threshold::Absolute(Byte::from_bytes(threshold)),
+        low_watermark: None,
+        max_idle: None,
+        interval: None,
+        debounce: None,
+        volume_threshold: None,
+        volume_max_idle: None,
+        remove_exited_after: None,
+        backend: BackendKind::Docker,
+        dry_run: false,
+        build_cache: false,
+        keep: vec![],
+        log_level: None,
+        state_path: None,
+        state_format: StateFormat::Yaml,
+    }
+}
+
+// Start a server which answers each request with the next of the given raw responses. The client
+// makes a new connection for each request. Returns a client for the server, and a handle which
+// yields the request lines once every response has been sent.
+#[cfg(unix)]
+pub fn serve(responses: &[&[u8]]) -> (Client, JoinHandle<Vec<String>>) {
+    let directory = TempDir::new();
+    let path = directory.path().join("api.sock");
+    let listener = UnixListener::bind(&path).unwrap();
+    let client = Client::new(&format!("unix://{}", path.display())).unwrap();
+    let responses = responses
+        .iter()
+        .map(|response| response.to_vec())
+        .collect::<Vec<_>>();
+
+    let handle = thread::spawn(move || {
+        let request_lines = responses
+            .iter()
+            .map(|response| {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+
+                // Read the request line and the headers. Requests never have a body.
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line == "\r\n" || line.is_empty() {
+                        break;
+                    }
+                }
+
+                reader.get_mut().write_all(response).unwrap();
+                request_line.trim_end().to_owned()
+            })
+            .collect();
+
+        // Delete the socket now that the server is done.
+        drop(directory);
+        request_lines
+    });
+
+    (client, handle)
+}
+
+// A successful response with the given JSON body
+#[cfg(unix)]
+pub fn json_response(body: &str) -> Vec<u8> {
+    format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+        body.len(),
+        body,
+    )
+    .into_bytes()
+}
@@ -1,4 +1,6 @@
+use chrono::{Local, TimeZone};
 use colored::{control::SHOULD_COLORIZE, ColoredString, Colorize};
+use std::{convert::TryFrom, time::Duration};
 
 // This trait has a function for formatting "code-like" text, such as an image name or a file path.
 // The reason it's implemented as a trait and not just a function is so we can use it with method
@@ -19,3 +21,18 @@ impl CodeStr for str {
         }
     }
 }
+
+// Format a timestamp, expressed as a duration since the UNIX epoch, in the local time zone.
+pub fn timestamp(duration: Duration) -> String {
+    i64::try_from(duration.as_secs())
+        .ok()
+        .and_then(|seconds| {
+            Local
+                .timestamp_opt(seconds, duration.subsec_nanos())
+                .single()
+        })
+        .map_or_else(
+            || format!("{:?}", duration),
+            |time| time.format("%Y-%m-%d %H:%M:%S %:z").to_string(),
+        )
+}
@@ -0,0 +1,407 @@
+use crate::format::CodeStr;
+use serde::{de::DeserializeOwned, Deserialize};
+use std::{
+    convert::TryFrom,
+    fmt::Write as _,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::TcpStream,
+};
+
+#[cfg(unix)]
+use std::{os::unix::net::UnixStream, path::PathBuf};
+
+// The characters which don't need to be percent-encoded in a URL path or query component
+const UNRESERVED_CHARACTERS: &str = "-._~/:@";
+
+// An address at which an HTTP server can be reached
+#[derive(Clone, Debug)]
+enum Endpoint {
+    #[cfg(unix)]
+    Unix(PathBuf),
+    Tcp(String),
+}
+
+// A connection to an HTTP server
+enum Connection {
+    #[cfg(unix)]
+    Unix(UnixStream),
+    Tcp(TcpStream),
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.read(buf),
+            Self::Tcp(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.write(buf),
+            Self::Tcp(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.flush(),
+            Self::Tcp(stream) => stream.flush(),
+        }
+    }
+}
+
+// The body of an error response from the Docker Engine API or the Libpod API
+#[derive(Deserialize, Debug)]
+struct ErrorMessage {
+    message: String,
+}
+
+// A minimal HTTP/1.1 client for talking to the Docker Engine API and compatible APIs. Each request
+// opens a new connection, which keeps things simple and is cheap for local sockets.
+#[derive(Clone, Debug)]
+pub struct Client {
+    endpoint: Endpoint,
+}
+
+impl Client {
+    // Construct a client for a host of the form `unix:///path/to/socket` or `tcp://host:port`.
+    pub fn new(host: &str) -> io::Result<Self> {
+        #[cfg(unix)]
+        {
+            if let Some(path) = host.strip_prefix("unix://") {
+                return Ok(Self {
+                    endpoint: Endpoint::Unix(PathBuf::from(path)),
+                });
+            }
+        }
+
+        if let Some(address) = host.strip_prefix("tcp://") {
+            return Ok(Self {
+                endpoint: Endpoint::Tcp(address.trim_end_matches('/').to_owned()),
+            });
+        }
+
+        Err(io::Error::other(format!(
+            "Unsupported host {}.",
+            host.code_str(),
+        )))
+    }
+
+    // Open a new connection to the server.
+    fn connect(&self) -> io::Result<Connection> {
+        match &self.endpoint {
+            #[cfg(unix)]
+            Endpoint::Unix(path) => UnixStream::connect(path).map(Connection::Unix),
+            Endpoint::Tcp(address) => TcpStream::connect(address).map(Connection::Tcp),
+        }
+    }
+
+    // Send a request and return the response body if the server indicated success.
+    pub fn request(&self, method: &str, path: &str) -> io::Result<Response> {
+        debug!(
+            "Sending request {}\u{2026}",
+            format!("{} {}", method, path).code_str()
+        );
+
+        // Send the request. We ask the server to close the connection when it's done so that the
+        // end of the response is unambiguous even if the server doesn't tell us its length.
+        let mut connection = self.connect()?;
+        write!(
+            connection,
+            "{} {} HTTP/1.1\r\nHost: docker\r\nConnection: close\r\nContent-Length: 0\r\n\r\n",
+            method, path,
+        )?;
+        connection.flush()?;
+
+        // Read the status line and headers.
+        let mut reader = BufReader::new(connection);
+        let status = read_status(&mut reader)?;
+        let mut chunked = false;
+        let mut content_length = None;
+        loop {
+            let line = read_line(&mut reader)?;
+            if line.is_empty() {
+                break;
+            }
+
+            if let Some((name, value)) = line.split_once(':') {
+                let value = value.trim();
+                if name.eq_ignore_ascii_case("transfer-encoding") {
+                    chunked = value.eq_ignore_ascii_case("chunked");
+                } else if name.eq_ignore_ascii_case("content-length") {
+                    content_length = Some(value.parse::<u64>().map_err(|_| {
+                        io::Error::other(format!("Invalid content length {}.", value.code_str()))
+                    })?);
+                }
+            }
+        }
+
+        // Figure out how to delimit the body.
+        let body: Box<dyn BufRead + Send> = if chunked {
+            Box::new(BufReader::new(ChunkedReader::new(reader)))
+        } else if let Some(content_length) = content_length {
+            Box::new(reader.take(content_length))
+        } else {
+            Box::new(reader)
+        };
+        let mut response = Response { body };
+
+        // Turn error responses into errors.
+        if !(200..300).contains(&status) {
+            let mut payload = String::new();
+            response.body.read_to_string(&mut payload)?;
+            let message = serde_json::from_str::<ErrorMessage>(&payload)
+                .map_or_else(|_| payload.trim().to_owned(), |error| error.message);
+
+            // Callers can tell when something doesn't exist (e.g., because it was removed in the
+            // meantime) by the kind of the error.
+            return Err(io::Error::new(
+                if status == 404 {
+                    io::ErrorKind::NotFound
+                } else {
+                    io::ErrorKind::Other
+                },
+                format!(
+                    "{} failed with status {}: {}",
+                    format!("{} {}", method, path).code_str(),
+                    status,
+                    message,
+                ),
+            ));
+        }
+
+        Ok(response)
+    }
+
+    // Send a request and discard the response body.
+    pub fn request_empty(&self, method: &str, path: &str) -> io::Result<()> {
+        io::copy(&mut self.request(method, path)?.body, &mut io::sink()).map(|_| ())
+    }
+
+    // Send a request and deserialize the JSON response body.
+    pub fn request_json<T: DeserializeOwned>(&self, method: &str, path: &str) -> io::Result<T> {
+        let mut payload = String::new();
+        self.request(method, path)?
+            .body
+            .read_to_string(&mut payload)?;
+
+        serde_json::from_str(&payload).map_err(io::Error::other)
+    }
+}
+
+// The body of a successful response
+pub struct Response {
+    pub body: Box<dyn BufRead + Send>,
+}
+
+// Read a line terminated by CRLF, without the terminator.
+fn read_line<R: BufRead>(reader: &mut R) -> io::Result<String> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "The connection was closed unexpectedly.",
+        ));
+    }
+
+    Ok(line.trim_end_matches(&['\r', '\n'][..]).to_owned())
+}
+
+// Read the status line of a response and return the status code.
+fn read_status<R: BufRead>(reader: &mut R) -> io::Result<u16> {
+    let line = read_line(reader)?;
+    line.split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| io::Error::other(format!("Invalid status line {}.", line.code_str())))
+}
+
+// A reader which decodes a body sent with `Transfer-Encoding: chunked`
+struct ChunkedReader<R: BufRead> {
+    inner: R,
+    remaining: u64,
+    done: bool,
+}
+
+impl<R: BufRead> ChunkedReader<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            remaining: 0,
+            done: false,
+        }
+    }
+}
+
+impl<R: BufRead> Read for ChunkedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+
+        // Read the size of the next chunk, if needed. Chunk extensions are ignored.
+        if self.remaining == 0 {
+            let line = read_line(&mut self.inner)?;
+            let size = line.split(';').next().unwrap_or("").trim();
+            self.remaining = u64::from_str_radix(size, 16).map_err(|_| {
+                io::Error::other(format!("Invalid chunk size {}.", size.code_str()))
+            })?;
+
+            // A chunk of size zero marks the end of the body. There may be trailers after it, but
+            // we don't need them.
+            if self.remaining == 0 {
+                self.done = true;
+                return Ok(0);
+            }
+        }
+
+        // Read as much of the current chunk as we can.
+        let limit = buf
+            .len()
+            .min(usize::try_from(self.remaining).unwrap_or(usize::MAX));
+        let bytes_read = self.inner.read(&mut buf[..limit])?;
+        if bytes_read == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "The connection was closed in the middle of a chunk.",
+            ));
+        }
+        self.remaining -= bytes_read as u64;
+
+        // Consume the CRLF which follows each chunk.
+        if self.remaining == 0 {
+            read_line(&mut self.inner)?;
+        }
+
+        Ok(bytes_read)
+    }
+}
+
+// Percent-encode a string for use in a URL path or query component.
+pub fn encode(input: &str) -> String {
+    let mut output = String::new();
+    for byte in input.bytes() {
+        if byte.is_ascii_alphanumeric() || UNRESERVED_CHARACTERS.as_bytes().contains(&byte) {
+            output.push(char::from(byte));
+        } else {
+            // The `unwrap` is safe because writing to a `String` never fails.
+            write!(output, "%{:02X}", byte).unwrap();
+        }
+    }
+
+    output
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::encode;
+    use crate::fixtures::serve;
+    use serde::Deserialize;
+    use std::io::{self, Read};
+
+    #[derive(Deserialize, Debug, Eq, PartialEq)]
+    struct Payload {
+        value: u32,
+    }
+
+    #[test]
+    fn content_length_delimits_body() {
+        let (client, handle) =
+            serve(&[b"HTTP/1.1 200 OK\r\nContent-Length: 11\r\n\r\n{\"value\":1}trailing garbage"]);
+
+        let payload = client.request_json::<Payload>("GET", "/foo").unwrap();
+        assert_eq!(payload, Payload { value: 1 });
+        assert_eq!(handle.join().unwrap(), vec!["GET /foo HTTP/1.1"]);
+    }
+
+    #[test]
+    fn chunked_body_is_decoded() {
+        let (client, handle) = serve(&[b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+              4\r\n{\"va\r\n6;ext=1\r\nlue\":2\r\n1\r\n}\r\n0\r\n\r\n"]);
+
+        let payload = client.request_json::<Payload>("GET", "/bar").unwrap();
+        assert_eq!(payload, Payload { value: 2 });
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn body_without_length_ends_with_connection() {
+        let (client, handle) = serve(&[b"HTTP/1.1 200 OK\r\n\r\n{\"value\":3}"]);
+
+        let payload = client.request_json::<Payload>("GET", "/baz").unwrap();
+        assert_eq!(payload, Payload { value: 3 });
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn error_status_uses_error_message() {
+        let (client, handle) = serve(
+            &[b"HTTP/1.1 409 Conflict\r\nContent-Length: 31\r\n\r\n{\"message\":\"image is in use\"}\r\n"],
+        );
+
+        let error = client.request_empty("DELETE", "/images/x").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::Other);
+        assert!(error
+            .to_string()
+            .ends_with("failed with status 409: image is in use"));
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn error_status_without_json_uses_body() {
+        let (client, handle) =
+            serve(&[b"HTTP/1.1 500 Internal Server Error\r\nContent-Length: 6\r\n\r\noops\r\n"]);
+
+        let error = client.request_empty("GET", "/info").unwrap_err();
+        assert!(error.to_string().ends_with("failed with status 500: oops"));
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn not_found_status_is_not_found_error() {
+        let (client, handle) = serve(
+            &[b"HTTP/1.1 404 Not Found\r\nContent-Length: 31\r\n\r\n{\"message\":\"No such container\"}"],
+        );
+
+        let error = client
+            .request_empty("GET", "/containers/x/json")
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        assert!(error.to_string().ends_with("No such container"));
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn eof_in_chunk_is_error() {
+        let (client, handle) =
+            serve(&[b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n10\r\n{\"value\""]);
+
+        let mut body = client.request("GET", "/events").unwrap().body;
+        handle.join().unwrap();
+        let error = body.read_to_end(&mut vec![]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn eof_before_status_is_error() {
+        let (client, handle) = serve(&[b""]);
+
+        let error = client.request("GET", "/info").err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn encode_escapes_reserved_characters() {
+        assert_eq!(
+            encode("sha256:abc/def ghi?{\"x\":1}"),
+            "sha256:abc/def%20ghi%3F%7B%22x%22:1%7D",
+        );
+    }
+}
@@ -0,0 +1,197 @@
+use crate::{backend::Image, format::CodeStr};
+use std::{collections::HashMap, fmt, io, str::FromStr};
+
+// A rule which protects matching images from being deleted
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum KeepRule {
+    // A glob pattern which is matched against the `repository:tag` and the repository of each tag
+    Pattern(String),
+
+    // A label which must be present, optionally with a specific value
+    Label(String, Option<String>),
+}
+
+impl KeepRule {
+    // Parse a label rule of the form `KEY` or `KEY=VALUE`.
+    pub fn label(rule: &str) -> io::Result<Self> {
+        let mut parts = rule.splitn(2, '=');
+
+        // The `unwrap` is safe because `splitn` always produces at least one part.
+        let key = parts.next().unwrap();
+        if key.is_empty() {
+            return Err(io::Error::other(format!(
+                "Invalid label rule {}.",
+                rule.code_str(),
+            )));
+        }
+
+        Ok(Self::Label(
+            key.to_owned(),
+            parts.next().map(ToOwned::to_owned),
+        ))
+    }
+
+    // Determine whether this rule protects an image.
+    pub fn matches(&self, image: &Image) -> bool {
+        match self {
+            Self::Pattern(pattern) => image.repo_tags.iter().any(|repo_tag| {
+                glob_matches(pattern, repo_tag)
+                    || repository(repo_tag)
+                        .is_some_and(|repository| glob_matches(pattern, repository))
+            }),
+            Self::Label(..) => self.matches_labels(&image.labels),
+        }
+    }
+
+    // Determine whether this is a label rule which matches the given labels.
+    pub fn matches_labels(&self, labels: &HashMap<String, String>) -> bool {
+        match self {
+            Self::Pattern(_) => false,
+            Self::Label(key, value) => labels
+                .get(key)
+                .is_some_and(|actual| value.as_ref().map_or(true, |expected| expected == actual)),
+        }
+    }
+}
+
+impl FromStr for KeepRule {
+    type Err = io::Error;
+
+    // Parse a pattern rule.
+    fn from_str(pattern: &str) -> io::Result<Self> {
+        if pattern.is_empty() {
+            return Err(io::Error::other("Keep patterns must not be empty."));
+        }
+
+        Ok(Self::Pattern(pattern.to_owned()))
+    }
+}
+
+impl fmt::Display for KeepRule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Pattern(pattern) => write!(f, "{}", pattern),
+            Self::Label(key, None) => write!(f, "label {}", key),
+            Self::Label(key, Some(value)) => write!(f, "label {}={}", key, value),
+        }
+    }
+}
+
+// Find the first rule which protects an image, if any.
+pub fn protecting_rule<'a>(rules: &'a [KeepRule], image: &Image) -> Option<&'a KeepRule> {
+    rules.iter().find(|rule| rule.matches(image))
+}
+
+// Extract the repository from a `repository:tag` string. The repository may contain a colon itself
+// if it includes a registry port, so we only split on a colon after the last slash.
+fn repository(repo_tag: &str) -> Option<&str> {
+    let name_start = repo_tag.rfind('/').map_or(0, |index| index + 1);
+    repo_tag[name_start..]
+        .rfind(':')
+        .map(|index| &repo_tag[..name_start + index])
+}
+
+// Match a string against a glob pattern, in which `*` matches any sequence of characters and `?`
+// matches any single character.
+fn glob_matches(pattern: &str, input: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let input = input.chars().collect::<Vec<_>>();
+
+    // Match greedily, remembering the most recent `*` so we can backtrack to it.
+    let (mut p, mut i) = (0, 0);
+    let mut backtrack = None;
+    while i < input.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == input[i]) {
+            p += 1;
+            i += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, i));
+            p += 1;
+        } else if let Some((star, position)) = backtrack {
+            // Let the `*` consume one more character and try again.
+            p = star + 1;
+            i = position + 1;
+            backtrack = Some((star, position + 1));
+        } else {
+            return false;
+        }
+    }
+
+    // Any remaining pattern characters must all be `*`s.
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{glob_matches, repository, KeepRule};
+    use crate::backend::Image;
+    use std::{collections::HashMap, str::FromStr};
+
+    #[test]
+    fn glob_matches_literals() {
+        assert!(glob_matches("ubuntu:22.04", "ubuntu:22.04"));
+        assert!(!glob_matches("ubuntu:22.04", "ubuntu:22.10"));
+        assert!(!glob_matches("ubuntu", "ubuntu:22.04"));
+        assert!(glob_matches("", ""));
+    }
+
+    #[test]
+    fn glob_matches_wildcards() {
+        assert!(glob_matches("ci/base:*", "ci/base:1"));
+        assert!(glob_matches("ci/base:*", "ci/base:"));
+        assert!(!glob_matches("ci/base:*", "ci/other:1"));
+        assert!(glob_matches("ubuntu:2?.04", "ubuntu:22.04"));
+        assert!(!glob_matches("ubuntu:2?.04", "ubuntu:2.04"));
+        assert!(glob_matches("*", ""));
+        assert!(glob_matches("*/*:latest", "registry/app:latest"));
+    }
+
+    #[test]
+    fn glob_matches_backtracks() {
+        assert!(glob_matches("*a*b", "xaxxaxb"));
+        assert!(glob_matches("a*b*c", "abbbcbc"));
+        assert!(!glob_matches("a*b*c", "abbbcb"));
+        assert!(glob_matches("**x", "yyx"));
+    }
+
+    #[test]
+    fn repository_strips_tag() {
+        assert_eq!(repository("ubuntu:22.04"), Some("ubuntu"));
+        assert_eq!(repository("ci/base:1"), Some("ci/base"));
+        assert_eq!(
+            repository("registry:5000/app:latest"),
+            Some("registry:5000/app"),
+        );
+        assert_eq!(repository("registry:5000/app"), None);
+        assert_eq!(repository("ubuntu"), None);
+    }
+
+    #[test]
+    fn pattern_matches_repository_or_tag() {
+        let image = Image {
+            id: "sha256:abc".to_owned(),
+            repo_tags: vec!["registry:5000/app:1.2".to_owned()],
+            labels: HashMap::new(),
+        };
+
+        assert!(KeepRule::from_str("registry:5000/app")
+            .unwrap()
+            .matches(&image));
+        assert!(KeepRule::from_str("*:1.?").unwrap().matches(&image));
+        assert!(!KeepRule::from_str("app").unwrap().matches(&image));
+        assert!(KeepRule::from_str("").is_err());
+    }
+
+    #[test]
+    fn label_rules_match_key_and_value() {
+        let labels = vec![("team".to_owned(), "ci".to_owned())]
+            .into_iter()
+            .collect::<HashMap<_, _>>();
+
+        assert!(KeepRule::label("team").unwrap().matches_labels(&labels));
+        assert!(KeepRule::label("team=ci").unwrap().matches_labels(&labels));
+        assert!(!KeepRule::label("team=web").unwrap().matches_labels(&labels));
+        assert!(!KeepRule::label("owner").unwrap().matches_labels(&labels));
+        assert!(KeepRule::label("=ci").is_err());
+    }
+}
@@ -0,0 +1,120 @@
+use crate::{backend::Image, duration, format::CodeStr};
+use std::time::Duration;
+
+// Labels which image authors can use to influence how Docuum treats their images
+pub const KEEP_LABEL: &str = "docuum.keep";
+pub const PRIORITY_LABEL: &str = "docuum.priority";
+pub const MAX_IDLE_LABEL: &str = "docuum.max-idle";
+
+// The eviction policy specified by the labels of an image
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LabelPolicy {
+    // Whether the image is protected from deletion
+    pub keep: bool,
+
+    // Images with lower priorities are deleted before images with higher priorities, regardless of
+    // when they were last used.
+    pub priority: i64,
+
+    // How long the image may go unused before it's deleted, regardless of disk usage
+    pub max_idle: Option<Duration>,
+}
+
+impl LabelPolicy {
+    // Read the policy from the labels of an image. Invalid values are logged and ignored.
+    pub fn from_image(image: &Image) -> Self {
+        let mut policy = Self::default();
+
+        if let Some(keep) = image.labels.get(KEEP_LABEL) {
+            policy.keep = keep.trim().eq_ignore_ascii_case("true");
+        }
+
+        if let Some(priority) = image.labels.get(PRIORITY_LABEL) {
+            match priority.trim().parse() {
+                Ok(priority) => policy.priority = priority,
+                Err(_) => warn!(
+                    "Ignoring invalid {} label {} on image {}.",
+                    PRIORITY_LABEL.code_str(),
+                    priority.code_str(),
+                    image.id.code_str(),
+                ),
+            }
+        }
+
+        if let Some(max_idle) = image.labels.get(MAX_IDLE_LABEL) {
+            match duration::parse(max_idle) {
+                Ok(max_idle) => policy.max_idle = Some(max_idle),
+                Err(error) => warn!(
+                    "Ignoring invalid {} label on image {}. Details: {}",
+                    MAX_IDLE_LABEL.code_str(),
+                    image.id.code_str(),
+                    error,
+                ),
+            }
+        }
+
+        policy
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LabelPolicy, KEEP_LABEL, MAX_IDLE_LABEL, PRIORITY_LABEL};
+    use crate::backend::Image;
+    use std::time::Duration;
+
+    fn policy(labels: &[(&str, &str)]) -> LabelPolicy {
+        LabelPolicy::from_image(&Image {
+            id: "sha256:abc".to_owned(),
+            repo_tags: vec![],
+            labels: labels
+                .iter()
+                .map(|&(key, value)| (key.to_owned(), value.to_owned()))
+                .collect(),
+        })
+    }
+
+    #[test]
+    fn no_labels_give_the_default_policy() {
+        let policy = policy(&[("maintainer", "someone")]);
+
+        assert!(!policy.keep);
+        assert_eq!(policy.priority, 0);
+        assert_eq!(policy.max_idle, None);
+    }
+
+    #[test]
+    fn keep_label() {
+        assert!(policy(&[(KEEP_LABEL, "true")]).keep);
+        assert!(policy(&[(KEEP_LABEL, " TRUE ")]).keep);
+        assert!(!policy(&[(KEEP_LABEL, "false")]).keep);
+        assert!(!policy(&[(KEEP_LABEL, "yes")]).keep);
+    }
+
+    #[test]
+    fn priority_label() {
+        assert_eq!(policy(&[(PRIORITY_LABEL, "10")]).priority, 10);
+        assert_eq!(policy(&[(PRIORITY_LABEL, " -5 ")]).priority, -5);
+    }
+
+    #[test]
+    fn max_idle_label() {
+        assert_eq!(
+            policy(&[(MAX_IDLE_LABEL, "1d12h")]).max_idle,
+            Some(Duration::from_secs(36 * 60 * 60)),
+        );
+    }
+
+    #[test]
+    fn invalid_labels_are_ignored() {
+        let policy = policy(&[
+            (KEEP_LABEL, "true"),
+            (PRIORITY_LABEL, "high"),
+            (MAX_IDLE_LABEL, "forever"),
+        ]);
+
+        assert!(policy.keep);
+        assert_eq!(policy.priority, 0);
+        assert_eq!(policy.max_idle, None);
+    }
+}
@@ -1,23 +1,55 @@
+mod backend;
+mod config;
+mod containers;
+mod docker;
+mod duration;
+#[cfg(test)]
+mod fixtures;
 mod format;
+mod http;
+mod keep;
+mod labels;
+mod podman;
 mod run;
 mod state;
+mod state_log;
+mod status;
+mod threshold;
+mod touch;
+mod volumes;
 
-use crate::{format::CodeStr, run::run};
+use crate::{
+    backend::BackendKind,
+    format::CodeStr,
+    keep::KeepRule,
+    run::{run, vacuum_once, ReloadRequests},
+    state::StateFormat,
+    status::{status, OutputFormat},
+    threshold::Threshold,
+    touch::touch,
+};
 use atty::Stream;
-use byte_unit::Byte;
 use chrono::Local;
-use clap::{App, AppSettings, Arg};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
 use env_logger::{fmt::Color, Builder};
 use log::{Level, LevelFilter};
 use std::{
     env,
     io::{self, Write},
+    path::{Path, PathBuf},
     process::exit,
     str::FromStr,
+    sync::{Arc, Mutex},
     thread::sleep,
     time::Duration,
 };
 
+#[cfg(unix)]
+use {
+    signal_hook::{consts::SIGHUP, iterator::Signals},
+    std::thread,
+};
+
 #[macro_use]
 extern crate log;
 
@@ -27,25 +59,77 @@ const VERSION: &str = env!("CARGO_PKG_VERSION");
 // Defaults
 const DEFAULT_LOG_LEVEL: LevelFilter = LevelFilter::Info;
 const DEFAULT_THRESHOLD: &str = "10 GB";
+const DEFAULT_BACKEND: &str = "docker";
+const DEFAULT_STATE_FORMAT: &str = "yaml";
+const DEFAULT_OUTPUT_FORMAT: &str = "table";
 
 // Command-line argument and option names
+const CONFIG_ARG: &str = "config";
 const THRESHOLD_ARG: &str = "threshold";
+const LOW_WATERMARK_ARG: &str = "low-watermark";
+const MAX_IDLE_ARG: &str = "max-idle";
+const INTERVAL_ARG: &str = "interval";
+const DEBOUNCE_ARG: &str = "debounce";
+const VOLUME_THRESHOLD_ARG: &str = "volume-threshold";
+const VOLUME_MAX_IDLE_ARG: &str = "volume-max-idle";
+const REMOVE_EXITED_AFTER_ARG: &str = "remove-exited-after";
+const BACKEND_ARG: &str = "backend";
+const DRY_RUN_ARG: &str = "dry-run";
+const NO_DRY_RUN_ARG: &str = "no-dry-run";
+const BUILD_CACHE_ARG: &str = "build-cache";
+const NO_BUILD_CACHE_ARG: &str = "no-build-cache";
+const KEEP_ARG: &str = "keep";
+const KEEP_LABEL_ARG: &str = "keep-label";
+const STATE_PATH_ARG: &str = "state-path";
+const STATE_FORMAT_ARG: &str = "state-format";
+const FORMAT_ARG: &str = "format";
+const ONCE_ARG: &str = "once";
+const IMAGE_ARG: &str = "IMAGE";
+
+// Subcommand names
+const STATUS_COMMAND: &str = "status";
+const TOUCH_COMMAND: &str = "touch";
+const VACUUM_COMMAND: &str = "vacuum";
+
+// The exit status of `vacuum --once` if images still use more space than the threshold allows
+const OVER_THRESHOLD_EXIT_CODE: i32 = 2;
+
+// The environment variable which overrides the log level
+const LOG_LEVEL_ENV: &str = "LOG_LEVEL";
 
-// This struct represents the command-line arguments.
+// The environment variable which sets the path to the state file
+const STATE_PATH_ENV: &str = "DOCUUM_STATE";
+
+// This struct represents the settings from the command-line arguments and the configuration file.
 pub struct Settings {
-    threshold: Byte,
+    threshold: Threshold,
+    low_watermark: Option<Threshold>,
+    max_idle: Option<Duration>,
+    interval: Option<Duration>,
+    debounce: Option<Duration>,
+    volume_threshold: Option<Threshold>,
+    volume_max_idle: Option<Duration>,
+    remove_exited_after: Option<Duration>,
+    backend: BackendKind,
+    dry_run: bool,
+    build_cache: bool,
+    keep: Vec<KeepRule>,
+    log_level: Option<LevelFilter>,
+    state_path: Option<PathBuf>,
+    state_format: StateFormat,
+}
+
+// Read the log level from the environment, if it's set to something valid.
+fn log_level_from_env() -> Option<LevelFilter> {
+    env::var(LOG_LEVEL_ENV)
+        .ok()
+        .and_then(|level| LevelFilter::from_str(&level).ok())
 }
 
-// Set up the logger.
+// Set up the logger. The effective level can be changed afterward with `set_log_level`.
 fn set_up_logging() {
     Builder::new()
-        .filter_module(
-            module_path!(),
-            LevelFilter::from_str(
-                &env::var("LOG_LEVEL").unwrap_or_else(|_| DEFAULT_LOG_LEVEL.to_string()),
-            )
-            .unwrap_or_else(|_| DEFAULT_LOG_LEVEL),
-        )
+        .filter_module(module_path!(), LevelFilter::Trace)
         .format(|buf, record| {
             let mut style = buf.style();
             style.set_bold(true);
@@ -69,19 +153,27 @@ fn set_up_logging() {
                 "{} {}",
                 style.value(format!(
                     "[{} {}]",
-                    Local::now().format("%Y-%m-%d %H:%M:%S %:z").to_string(),
+                    Local::now().format("%Y-%m-%d %H:%M:%S %:z"),
                     record.level()
                 )),
-                record.args().to_string()
+                record.args()
             )
         })
         .init();
+
+    set_log_level(None);
+}
+
+// Set the log level. The environment takes precedence over the given level, which in turn takes
+// precedence over the default.
+fn set_log_level(level: Option<LevelFilter>) {
+    log::set_max_level(log_level_from_env().or(level).unwrap_or(DEFAULT_LOG_LEVEL));
 }
 
 // Parse the command-line arguments.
-fn settings() -> io::Result<Settings> {
+fn cli() -> ArgMatches<'static> {
     // Set up the command-line interface.
-    let matches = App::new("Docuum")
+    App::new("Docuum")
         .version(VERSION)
         .version_short("v")
         .author("Stephan Boyer <stephan@stephanboyer.com>")
@@ -89,34 +181,458 @@ fn settings() -> io::Result<Settings> {
         .setting(AppSettings::ColoredHelp)
         .setting(AppSettings::NextLineHelp)
         .setting(AppSettings::UnifiedHelpMessage)
+        .arg(
+            Arg::with_name(CONFIG_ARG)
+                .short("c")
+                .long(CONFIG_ARG)
+                .value_name("PATH")
+                .help(&format!(
+                    "Sets the path to the configuration file (default: {} or {})",
+                    "~/.config/docuum/config.yml".code_str(),
+                    "/etc/docuum.yml".code_str(),
+                ))
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name(THRESHOLD_ARG)
                 .short("t")
                 .long(THRESHOLD_ARG)
                 .value_name("THRESHOLD")
                 .help(&format!(
-                    "Sets the maximum amount of space to be used for Docker images (default: {})",
-                    DEFAULT_THRESHOLD.code_str()
+                    "Sets the maximum amount of space to be used for Docker images, as an amount \
+                     (e.g., {}), a percentage of the capacity of the filesystem containing the \
+                     images (e.g., {}), or an amount of space to keep free (e.g., {}) \
+                     (default: {})",
+                    "10 GB".code_str(),
+                    "80%".code_str(),
+                    "20 GB free".code_str(),
+                    DEFAULT_THRESHOLD.code_str(),
+                ))
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name(LOW_WATERMARK_ARG)
+                .long(LOW_WATERMARK_ARG)
+                .value_name("THRESHOLD")
+                .help(&format!(
+                    "Once the threshold is exceeded, deletes images until they use no more than \
+                     this, in any of the forms accepted by {} (default: the threshold)",
+                    format!("--{}", THRESHOLD_ARG).code_str(),
                 ))
                 .takes_value(true),
         )
-        .get_matches();
+        .arg(
+            Arg::with_name(MAX_IDLE_ARG)
+                .long(MAX_IDLE_ARG)
+                .value_name("DURATION")
+                .help(&format!(
+                    "Deletes images which haven't been used for longer than this (e.g., {} or \
+                     {}), regardless of the threshold; the {} label takes precedence",
+                    "30d".code_str(),
+                    "1w12h".code_str(),
+                    labels::MAX_IDLE_LABEL.code_str(),
+                ))
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name(INTERVAL_ARG)
+                .long(INTERVAL_ARG)
+                .value_name("DURATION")
+                .help(&format!(
+                    "Also checks the disk usage periodically (e.g., every {}), not just when \
+                     images are used",
+                    "1h".code_str(),
+                ))
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name(DEBOUNCE_ARG)
+                .long(DEBOUNCE_ARG)
+                .value_name("DURATION")
+                .help(&format!(
+                    "Waits until no images have been used for this long (e.g., {}) before \
+                     checking the disk usage, so bursts of activity only cause one check",
+                    "5s".code_str(),
+                ))
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name(VOLUME_THRESHOLD_ARG)
+                .long(VOLUME_THRESHOLD_ARG)
+                .value_name("THRESHOLD")
+                .help(&format!(
+                    "Deletes dangling anonymous volumes, least recently used first, when volumes \
+                     use more than this, in any of the forms accepted by {}",
+                    format!("--{}", THRESHOLD_ARG).code_str(),
+                ))
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name(VOLUME_MAX_IDLE_ARG)
+                .long(VOLUME_MAX_IDLE_ARG)
+                .value_name("DURATION")
+                .help(&format!(
+                    "Deletes dangling anonymous volumes which haven't been used for longer than \
+                     this (e.g., {})",
+                    "7d".code_str(),
+                ))
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name(REMOVE_EXITED_AFTER_ARG)
+                .long(REMOVE_EXITED_AFTER_ARG)
+                .value_name("DURATION")
+                .help(&format!(
+                    "Removes containers which exited longer ago than this (e.g., {}) so the space \
+                     used by their images can be reclaimed, unless they have the {} label or \
+                     match a {} rule",
+                    "7d".code_str(),
+                    format!("{}=true", labels::KEEP_LABEL).code_str(),
+                    format!("--{}", KEEP_LABEL_ARG).code_str(),
+                ))
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name(BACKEND_ARG)
+                .short("b")
+                .long(BACKEND_ARG)
+                .value_name("BACKEND")
+                .help(&format!(
+                    "Sets the container runtime to manage (default: {})",
+                    DEFAULT_BACKEND.code_str()
+                ))
+                .possible_values(BackendKind::NAMES)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name(DRY_RUN_ARG)
+                .long(DRY_RUN_ARG)
+                .overrides_with(NO_DRY_RUN_ARG)
+                .help("Reports which images would be deleted without deleting them"),
+        )
+        .arg(
+            Arg::with_name(NO_DRY_RUN_ARG)
+                .long(NO_DRY_RUN_ARG)
+                .overrides_with(DRY_RUN_ARG)
+                .help(&format!(
+                    "Deletes images even if the configuration file enables {}",
+                    "dry-run".code_str(),
+                )),
+        )
+        .arg(
+            Arg::with_name(BUILD_CACHE_ARG)
+                .long(BUILD_CACHE_ARG)
+                .overrides_with(NO_BUILD_CACHE_ARG)
+                .help(
+                    "Counts the build cache toward the threshold and deletes build cache entries, \
+                     least recently used first, before deleting any images",
+                ),
+        )
+        .arg(
+            Arg::with_name(NO_BUILD_CACHE_ARG)
+                .long(NO_BUILD_CACHE_ARG)
+                .overrides_with(BUILD_CACHE_ARG)
+                .help(&format!(
+                    "Leaves the build cache alone even if the configuration file enables {}",
+                    "build-cache".code_str(),
+                )),
+        )
+        .arg(
+            Arg::with_name(KEEP_ARG)
+                .short("k")
+                .long(KEEP_ARG)
+                .value_name("PATTERN")
+                .help(&format!(
+                    "Prevents deletion of images with a matching {} or repository (e.g., {}); \
+                     {} matches any sequence of characters and {} matches any single character",
+                    "repository:tag".code_str(),
+                    "ci/base:*".code_str(),
+                    "*".code_str(),
+                    "?".code_str(),
+                ))
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name(KEEP_LABEL_ARG)
+                .long(KEEP_LABEL_ARG)
+                .value_name("KEY[=VALUE]")
+                .help("Prevents deletion of images with a matching label")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name(STATE_PATH_ARG)
+                .long(STATE_PATH_ARG)
+                .value_name("PATH")
+                .help(&format!(
+                    "Sets the path to the state file, which can also be set with the {} \
+                     environment variable (default: {})",
+                    STATE_PATH_ENV.code_str(),
+                    "~/.local/share/docuum/state.yml".code_str(),
+                ))
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name(STATE_FORMAT_ARG)
+                .long(STATE_FORMAT_ARG)
+                .value_name("FORMAT")
+                .help(&format!(
+                    "Sets how the state is stored: {} rewrites a YAML file every time, and {} \
+                     appends changes to a log, which is faster with many images (default: {})",
+                    "yaml".code_str(),
+                    "log".code_str(),
+                    DEFAULT_STATE_FORMAT.code_str(),
+                ))
+                .possible_values(StateFormat::NAMES)
+                .takes_value(true),
+        )
+        .subcommand(
+            SubCommand::with_name(STATUS_COMMAND)
+                .about(
+                    "Prints every image with its last-used time and the order in which images \
+                     would be deleted, without deleting anything",
+                )
+                .setting(AppSettings::ColoredHelp)
+                .setting(AppSettings::NextLineHelp)
+                .arg(
+                    Arg::with_name(FORMAT_ARG)
+                        .short("f")
+                        .long(FORMAT_ARG)
+                        .value_name("FORMAT")
+                        .help(&format!(
+                            "Sets the output format (default: {})",
+                            DEFAULT_OUTPUT_FORMAT.code_str(),
+                        ))
+                        .possible_values(OutputFormat::NAMES)
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(TOUCH_COMMAND)
+                .about("Marks images as used now, so they're kept longer")
+                .setting(AppSettings::ColoredHelp)
+                .setting(AppSettings::NextLineHelp)
+                .arg(
+                    Arg::with_name(IMAGE_ARG)
+                        .help("The images to mark, by ID or by name (e.g., ubuntu:22.04)")
+                        .required(true)
+                        .multiple(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(VACUUM_COMMAND)
+                .about("Vacuums whenever images are used, which is the default")
+                .setting(AppSettings::ColoredHelp)
+                .setting(AppSettings::NextLineHelp)
+                .arg(Arg::with_name(ONCE_ARG).long(ONCE_ARG).help(&format!(
+                    "Vacuums once and exits rather than listening for events. The exit \
+                             status is {} if images are within the threshold afterward, {} if \
+                             they still exceed it, and {} if an error occurs.",
+                    "0".code_str(),
+                    OVER_THRESHOLD_EXIT_CODE.to_string().code_str(),
+                    "1".code_str(),
+                ))),
+        )
+        .get_matches()
+}
+
+// Read a setting from the command line, or else from the configuration file.
+fn value<T>(
+    matches: &ArgMatches,
+    arg: &str,
+    parse: impl Fn(&str) -> io::Result<T>,
+    config: Option<T>,
+) -> io::Result<Option<T>> {
+    match matches.value_of(arg) {
+        Some(value) => parse(value).map(Some),
+        None => Ok(config),
+    }
+}
+
+// Read a setting which can be given more than once from the command line, or else from the
+// configuration file.
+fn values<T>(
+    matches: &ArgMatches,
+    arg: &str,
+    parse: impl Fn(&str) -> io::Result<T>,
+    config: Option<Vec<T>>,
+) -> io::Result<Vec<T>> {
+    if matches.is_present(arg) {
+        matches
+            .values_of(arg)
+            .into_iter()
+            .flatten()
+            .map(parse)
+            .collect()
+    } else {
+        Ok(config.unwrap_or_default())
+    }
+}
+
+// Read a setting which can be turned on or off on the command line, or else in the configuration
+// file. It's off by default. If both flags are given, the last one wins.
+fn flag(matches: &ArgMatches, on_arg: &str, off_arg: &str, config: Option<bool>) -> bool {
+    if matches.is_present(on_arg) {
+        true
+    } else if matches.is_present(off_arg) {
+        false
+    } else {
+        config.unwrap_or(false)
+    }
+}
+
+// Read the configuration file and combine it with the command-line arguments. This is called again
+// to reload the settings when the program receives `SIGHUP`.
+fn settings(matches: &ArgMatches) -> io::Result<Settings> {
+    // Read the configuration file. Settings from the command line take precedence over it.
+    let config = config::load(matches.value_of(CONFIG_ARG).map(Path::new))?;
 
     // Read the threshold.
-    let default_threshold = Byte::from_str(DEFAULT_THRESHOLD).unwrap(); // Manually verified safe
-    let threshold = matches.value_of(THRESHOLD_ARG).map_or_else(
-        || Ok(default_threshold),
-        |threshold| {
-            Byte::from_str(threshold).map_err(|_| {
-                io::Error::new(
-                    io::ErrorKind::Other,
-                    format!("Invalid threshold {}.", threshold.code_str()),
-                )
-            })
-        },
+    let threshold = match matches.value_of(THRESHOLD_ARG) {
+        Some(threshold) => Threshold::from_str(threshold)?,
+        None => config
+            .threshold
+            .unwrap_or_else(|| Threshold::from_str(DEFAULT_THRESHOLD).unwrap()), // Manually verified safe
+    };
+
+    // Read the low watermark.
+    let low_watermark = value(
+        matches,
+        LOW_WATERMARK_ARG,
+        Threshold::from_str,
+        config.low_watermark,
+    )?;
+    if let (Threshold::Absolute(threshold), Some(Threshold::Absolute(low_watermark))) =
+        (threshold, low_watermark)
+    {
+        if low_watermark > threshold {
+            return Err(io::Error::other(format!(
+                "The low watermark {} must not exceed the threshold {}.",
+                low_watermark
+                    .get_appropriate_unit(false)
+                    .to_string()
+                    .code_str(),
+                threshold.get_appropriate_unit(false).to_string().code_str(),
+            )));
+        }
+    }
+
+    // Read the maximum idle time.
+    let max_idle = value(matches, MAX_IDLE_ARG, duration::parse, config.max_idle)?;
+
+    // Read the interval.
+    let interval = value(matches, INTERVAL_ARG, duration::parse, config.interval)?;
+    if interval == Some(Duration::from_secs(0)) {
+        return Err(io::Error::other("The interval must be positive."));
+    }
+
+    // Read the debounce window. A window of zero means we don't debounce.
+    let debounce = value(matches, DEBOUNCE_ARG, duration::parse, config.debounce)?
+        .filter(|debounce| *debounce > Duration::from_secs(0));
+
+    // Read the volume policy.
+    let volume_threshold = value(
+        matches,
+        VOLUME_THRESHOLD_ARG,
+        Threshold::from_str,
+        config.volume_threshold,
+    )?;
+    let volume_max_idle = value(
+        matches,
+        VOLUME_MAX_IDLE_ARG,
+        duration::parse,
+        config.volume_max_idle,
+    )?;
+
+    // Read how long to keep exited containers.
+    let remove_exited_after = value(
+        matches,
+        REMOVE_EXITED_AFTER_ARG,
+        duration::parse,
+        config.remove_exited_after,
     )?;
 
-    Ok(Settings { threshold })
+    // Read the backend.
+    let backend = match matches.value_of(BACKEND_ARG) {
+        Some(backend) => BackendKind::from_str(backend)?,
+        None => match config.backend {
+            Some(backend) => backend,
+            None => BackendKind::from_str(DEFAULT_BACKEND)?,
+        },
+    };
+
+    // Read the keep rules. Rules given on the command line replace those in the configuration file,
+    // separately for patterns and labels.
+    let patterns = values(matches, KEEP_ARG, KeepRule::from_str, config.keep)?;
+    let labels = values(matches, KEEP_LABEL_ARG, KeepRule::label, config.keep_labels)?;
+
+    // Read the path to the state file. The command line takes precedence over the environment,
+    // which takes precedence over the configuration file.
+    let state_path = matches
+        .value_of_os(STATE_PATH_ARG)
+        .map(PathBuf::from)
+        .or_else(|| {
+            env::var_os(STATE_PATH_ENV)
+                .filter(|path| !path.is_empty())
+                .map(PathBuf::from)
+        })
+        .or(config.state_path);
+
+    // Read the state format.
+    let state_format = match matches.value_of(STATE_FORMAT_ARG) {
+        Some(state_format) => StateFormat::from_str(state_format)?,
+        None => match config.state_format {
+            Some(state_format) => state_format,
+            None => StateFormat::from_str(DEFAULT_STATE_FORMAT)?,
+        },
+    };
+
+    Ok(Settings {
+        threshold,
+        low_watermark,
+        max_idle,
+        interval,
+        debounce,
+        volume_threshold,
+        volume_max_idle,
+        remove_exited_after,
+        backend,
+        dry_run: flag(matches, DRY_RUN_ARG, NO_DRY_RUN_ARG, config.dry_run),
+        build_cache: flag(
+            matches,
+            BUILD_CACHE_ARG,
+            NO_BUILD_CACHE_ARG,
+            config.build_cache,
+        ),
+        keep: patterns.into_iter().chain(labels).collect(),
+        log_level: config.log_level,
+        state_path,
+        state_format,
+    })
+}
+
+// Ask the main loop to reload the settings whenever the program receives `SIGHUP`.
+#[cfg(unix)]
+fn handle_reload_signals(reload_requests: Arc<Mutex<ReloadRequests>>) -> io::Result<()> {
+    let mut signals = Signals::new([SIGHUP])?;
+
+    thread::spawn(move || {
+        for _ in signals.forever() {
+            // The `unwrap` is safe because the lock is never held across a panic.
+            reload_requests.lock().unwrap().request();
+        }
+    });
+
+    Ok(())
+}
+
+// Reloading isn't supported on this platform.
+#[cfg(not(unix))]
+fn handle_reload_signals(_reload_requests: Arc<Mutex<ReloadRequests>>) -> io::Result<()> {
+    Ok(())
 }
 
 // Let the fun begin!
@@ -127,8 +643,9 @@ fn main() {
     // Set up the logger.
     set_up_logging();
 
-    // Parse the command-line arguments.
-    let settings = match settings() {
+    // Parse the command-line arguments and read the configuration file.
+    let matches = cli();
+    let mut settings = match settings(&matches) {
         Ok(settings) => settings,
         Err(error) => {
             error!("{}", error);
@@ -136,21 +653,107 @@ fn main() {
         }
     };
 
+    // Apply the log level from the configuration file.
+    set_log_level(settings.log_level);
+
+    // Print the status instead of running the daemon, if requested.
+    if let Some(status_matches) = matches.subcommand_matches(STATUS_COMMAND) {
+        if let Err(error) = OutputFormat::from_str(
+            status_matches
+                .value_of(FORMAT_ARG)
+                .unwrap_or(DEFAULT_OUTPUT_FORMAT),
+        )
+        .and_then(|format| status(&settings, format))
+        {
+            error!("{}", error);
+            exit(1);
+        }
+
+        return;
+    }
+
+    // Mark images as used instead of running the daemon, if requested.
+    if let Some(touch_matches) = matches.subcommand_matches(TOUCH_COMMAND) {
+        // The `unwrap` is safe because the argument is required.
+        let images = touch_matches
+            .values_of(IMAGE_ARG)
+            .unwrap()
+            .collect::<Vec<_>>();
+        if let Err(error) = touch(&settings, &images) {
+            error!("{}", error);
+            exit(1);
+        }
+
+        return;
+    }
+
+    // Open the store in which the state is persisted.
+    let mut store = match settings.state_format.open(settings.state_path.as_deref()) {
+        Ok(store) => store,
+        Err(error) => {
+            error!("{}", error);
+            exit(1);
+        }
+    };
+
     // Try to load the state from disk.
-    let mut state = state::load().unwrap_or_else(|error| {
-        // We couldn't load any state from disk. Log the error.
-        debug!(
-            "Unable to load state from disk. Proceeding with initial state. Details: {}",
-            error.to_string().code_str()
-        );
+    let mut state = state::load(&mut *store).unwrap_or_else(|error| {
+        // We couldn't load any state from disk. That's expected on the first run, but otherwise the
+        // history of which images were used when is lost, so warn about it.
+        if error.kind() == io::ErrorKind::NotFound {
+            debug!(
+                "Unable to load state from disk. Proceeding with initial state. Details: {}",
+                error.to_string().code_str()
+            );
+        } else {
+            warn!(
+                "Unable to load state from disk. Proceeding with initial state. Details: {}",
+                error.to_string().code_str()
+            );
+        }
 
         // Start with the initial state.
         state::initial()
     });
 
-    // Stream Docker events and vacuum when necessary. Restart if an error occurs.
+    // Fail fast if the state can't be saved.
+    if let Err(error) = state::check_writable(store.path()) {
+        error!("{}", error);
+        exit(1);
+    }
+
+    // Vacuum just once, if requested.
+    if matches
+        .subcommand_matches(VACUUM_COMMAND)
+        .is_some_and(|vacuum_matches| vacuum_matches.is_present(ONCE_ARG))
+    {
+        match vacuum_once(&settings, &mut state, &mut *store) {
+            Ok(false) => exit(0),
+            Ok(true) => exit(OVER_THRESHOLD_EXIT_CODE),
+            Err(error) => {
+                error!("{}", error);
+                exit(1);
+            }
+        }
+    }
+
+    // Reload the settings on `SIGHUP`.
+    let reload_requests = Arc::new(Mutex::new(ReloadRequests::default()));
+    if let Err(error) = handle_reload_signals(reload_requests.clone()) {
+        error!("{}", error);
+        exit(1);
+    }
+    let reload = || crate::settings(&matches).inspect(|settings| set_log_level(settings.log_level));
+
+    // Stream events and vacuum when necessary. Restart if an error occurs.
     loop {
-        if let Err(e) = run(&settings, &mut state) {
+        if let Err(e) = run(
+            &mut settings,
+            &reload,
+            &mut state,
+            &mut store,
+            &reload_requests,
+        ) {
             error!("{}", e);
             info!("Restarting\u{2026}");
             sleep(Duration::from_secs(1));
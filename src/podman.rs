@@ -0,0 +1,353 @@
+use crate::{
+    backend::{Backend, Container, DiskUsage, Event, Image, ImageUsage, Layer, Volume},
+    docker::{
+        bytes, container_finished_at, image_layers, parse_events, ContainerRecord, ImageRecord,
+    },
+    format::CodeStr,
+    http::{encode, Client},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    env, io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+// The environment variable which tells us how to reach the Podman service
+const CONTAINER_HOST_ENV: &str = "CONTAINER_HOST";
+
+// The environment variable which tells us where the rootless Podman socket lives
+const XDG_RUNTIME_DIR_ENV: &str = "XDG_RUNTIME_DIR";
+
+// Where the rootful Podman service listens by default
+const DEFAULT_ROOTFUL_SOCKET: &str = "/run/podman/podman.sock";
+
+// An image as reported by the `/libpod/system/df` endpoint
+#[derive(Deserialize, Serialize, Debug)]
+struct ImageSpaceRecord {
+    #[serde(rename = "ImageID")]
+    pub image_id: String,
+
+    #[serde(rename = "Size")]
+    pub size: i64,
+
+    #[serde(rename = "SharedSize")]
+    pub shared_size: i64,
+}
+
+// A volume as reported by the `/libpod/system/df` endpoint
+#[derive(Deserialize, Serialize, Debug)]
+struct VolumeSpaceRecord {
+    #[serde(rename = "VolumeName")]
+    pub volume_name: String,
+
+    #[serde(rename = "Links")]
+    pub links: u64,
+
+    #[serde(rename = "Size")]
+    pub size: i64,
+}
+
+impl From<VolumeSpaceRecord> for Volume {
+    fn from(volume_space_record: VolumeSpaceRecord) -> Self {
+        Self {
+            anonymous: Self::has_anonymous_name(&volume_space_record.volume_name),
+            name: volume_space_record.volume_name,
+            size: bytes(volume_space_record.size),
+            containers: volume_space_record.links,
+        }
+    }
+}
+
+// The response from the `/libpod/system/df` endpoint
+#[derive(Deserialize, Serialize, Debug)]
+struct SpaceRecord {
+    #[serde(rename = "ImagesSize")]
+    pub images_size: i64,
+
+    #[serde(rename = "Images", default)]
+    pub images: Option<Vec<ImageSpaceRecord>>,
+
+    #[serde(rename = "Volumes", default)]
+    pub volumes: Option<Vec<VolumeSpaceRecord>>,
+}
+
+impl From<SpaceRecord> for DiskUsage {
+    fn from(space_record: SpaceRecord) -> Self {
+        Self {
+            total: bytes(space_record.images_size),
+            images: space_record
+                .images
+                .unwrap_or_default()
+                .into_iter()
+                .map(|image_space_record| {
+                    (
+                        image_space_record.image_id,
+                        ImageUsage {
+                            size: bytes(image_space_record.size),
+                            shared_size: bytes(image_space_record.shared_size),
+                        },
+                    )
+                })
+                .collect(),
+            build_cache: vec![],
+            volumes: space_record
+                .volumes
+                .unwrap_or_default()
+                .into_iter()
+                .map(Volume::from)
+                .collect(),
+        }
+    }
+}
+
+// The storage configuration reported by the `/libpod/info` endpoint
+#[derive(Deserialize, Serialize, Debug)]
+struct StoreRecord {
+    #[serde(rename = "graphRoot")]
+    pub graph_root: String,
+}
+
+// The response from the `/libpod/info` endpoint
+#[derive(Deserialize, Serialize, Debug)]
+struct InfoRecord {
+    #[serde(rename = "store")]
+    pub store: StoreRecord,
+}
+
+// Podman, accessed via the Libpod API
+pub struct Podman {
+    client: Client,
+}
+
+impl Podman {
+    // Connect to the service specified by `CONTAINER_HOST`, if it's set. Otherwise, connect to the
+    // rootless socket for the current user if it exists, or the rootful socket if not.
+    pub fn from_env() -> io::Result<Self> {
+        let host = env::var(CONTAINER_HOST_ENV)
+            .ok()
+            .filter(|host| !host.is_empty())
+            .unwrap_or_else(|| {
+                env::var(XDG_RUNTIME_DIR_ENV)
+                    .ok()
+                    .map(|runtime_dir| Path::new(&runtime_dir).join("podman/podman.sock"))
+                    .filter(|path| path.exists())
+                    .map_or_else(
+                        || format!("unix://{}", DEFAULT_ROOTFUL_SOCKET),
+                        |path| format!("unix://{}", path.to_string_lossy()),
+                    )
+            });
+
+        Ok(Self {
+            client: Client::new(&host)?,
+        })
+    }
+}
+
+// Translate Podman's event vocabulary into Docker's.
+fn normalize_event(mut event: Event) -> Event {
+    event.action = match (event.r#type.as_str(), event.action.as_str()) {
+        ("container", "remove") => "destroy".to_owned(),
+        ("image", "loadfromarchive") => "load".to_owned(),
+        _ => event.action,
+    };
+
+    event
+}
+
+impl Backend for Podman {
+    fn name(&self) -> &'static str {
+        "Podman"
+    }
+
+    fn inspect_image(&self, image: &str) -> io::Result<Image> {
+        // Query Podman for the image.
+        self.client
+            .request_json::<ImageRecord>("GET", &format!("/libpod/images/{}/json", encode(image)))
+            .map(Image::from)
+            .map_err(|error| {
+                io::Error::other(format!(
+                    "Unable to determine ID of image {}. Details: {}",
+                    image.code_str(),
+                    error,
+                ))
+            })
+    }
+
+    fn images(&self) -> io::Result<Vec<Image>> {
+        // Query Podman for the images.
+        self.client
+            .request_json::<Vec<ImageRecord>>("GET", "/libpod/images/json?all=true")
+            .map(|image_records| image_records.into_iter().map(Image::from).collect())
+            .map_err(|error| {
+                io::Error::other(format!(
+                    "Unable to determine IDs of all images. Details: {}",
+                    error,
+                ))
+            })
+    }
+
+    fn image_layers(&self, image_id: &str) -> io::Result<Vec<Layer>> {
+        image_layers(&self.client, "/libpod", image_id)
+    }
+
+    fn containers(&self) -> io::Result<Vec<Container>> {
+        // Query Podman for the containers.
+        self.client
+            .request_json::<Vec<ContainerRecord>>("GET", "/libpod/containers/json?all=true")
+            .map(|container_records| container_records.into_iter().map(Container::from).collect())
+            .map_err(|error| {
+                io::Error::other(format!(
+                    "Unable to determine IDs of images currently in use by containers. Details: {}",
+                    error,
+                ))
+            })
+    }
+
+    fn disk_usage(&self) -> io::Result<DiskUsage> {
+        // Query Podman for the space usage.
+        self.client
+            .request_json::<SpaceRecord>("GET", "/libpod/system/df")
+            .map(DiskUsage::from)
+            .map_err(|error| {
+                io::Error::other(format!(
+                    "Unable to determine the disk space used by Podman images. Details: {}",
+                    error,
+                ))
+            })
+    }
+
+    fn root_dir(&self) -> io::Result<PathBuf> {
+        // Ask Podman where it keeps its images.
+        self.client
+            .request_json::<InfoRecord>("GET", "/libpod/info")
+            .map(|info_record| PathBuf::from(info_record.store.graph_root))
+            .map_err(|error| {
+                io::Error::other(format!(
+                    "Unable to determine the Podman storage directory. Details: {}",
+                    error,
+                ))
+            })
+    }
+
+    fn delete_image(&self, image_id: &str) -> io::Result<()> {
+        // Tell Podman to delete the image. Podman requires `force` to delete an image with multiple
+        // tags, but `force` also removes any containers using the image, even running ones. So we
+        // only use it if no containers use the image. Otherwise, Podman refuses to delete it.
+        self.containers()
+            .and_then(|containers| {
+                let force = !containers
+                    .iter()
+                    .any(|container| container.image_id == image_id);
+
+                self.client.request_empty(
+                    "DELETE",
+                    &format!("/libpod/images/{}?force={}", encode(image_id), force),
+                )
+            })
+            .map_err(|error| {
+                io::Error::other(format!(
+                    "Unable to delete image {}. Details: {}",
+                    image_id.code_str(),
+                    error,
+                ))
+            })
+    }
+
+    fn delete_build_cache(&self, _id: &str) -> io::Result<()> {
+        // Podman doesn't report a build cache, so there's never anything to delete.
+        Err(io::Error::other(
+            "Podman doesn't support deleting build cache entries.",
+        ))
+    }
+
+    fn container_finished_at(&self, container_id: &str) -> io::Result<Option<Duration>> {
+        container_finished_at(&self.client, "/libpod", container_id)
+    }
+
+    fn delete_container(&self, container_id: &str) -> io::Result<()> {
+        // Tell Podman to delete the container. Its volumes are left alone.
+        self.client
+            .request_empty(
+                "DELETE",
+                &format!("/libpod/containers/{}", encode(container_id)),
+            )
+            .map_err(|error| {
+                io::Error::other(format!(
+                    "Unable to delete container {}. Details: {}",
+                    container_id.code_str(),
+                    error,
+                ))
+            })
+    }
+
+    fn delete_volume(&self, name: &str) -> io::Result<()> {
+        // Tell Podman to delete the volume.
+        self.client
+            .request_empty("DELETE", &format!("/libpod/volumes/{}", encode(name)))
+            .map_err(|error| {
+                io::Error::other(format!(
+                    "Unable to delete volume {}. Details: {}",
+                    name.code_str(),
+                    error,
+                ))
+            })
+    }
+
+    fn events(&self) -> io::Result<Box<dyn Iterator<Item = io::Result<Event>> + Send>> {
+        // Subscribe to the event stream.
+        let response = self.client.request("GET", "/libpod/events?stream=true")?;
+
+        Ok(Box::new(parse_events(response.body).map(|event_record| {
+            event_record.map(|event_record| normalize_event(Event::from(event_record)))
+        })))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::normalize_event;
+    use crate::{backend::Event, docker::parse_events};
+
+    #[test]
+    fn normalize_event_uses_docker_vocabulary() {
+        // Events as captured from the Podman event stream, and the actions Docker would report
+        let table = [
+            (
+                r#"{"status":"remove","id":"8c2a","from":"docker.io/library/alpine:latest","Type":"container","Action":"remove","Actor":{"ID":"8c2a","Attributes":{"containerExitCode":"0","image":"docker.io/library/alpine:latest","name":"test"}},"scope":"local","time":1700000000,"timeNano":1700000000123456789}"#,
+                "container",
+                "destroy",
+            ),
+            (
+                r#"{"status":"loadfromarchive","id":"","Type":"image","Action":"loadfromarchive","Actor":{"ID":"","Attributes":{"name":"/tmp/image.tar"}},"scope":"local","time":1700000001,"timeNano":1700000001123456789}"#,
+                "image",
+                "load",
+            ),
+            (
+                r#"{"status":"pull","id":"docker.io/library/alpine:latest","Type":"image","Action":"pull","Actor":{"ID":"docker.io/library/alpine:latest","Attributes":{"name":"docker.io/library/alpine:latest"}},"scope":"local","time":1700000002,"timeNano":1700000002123456789}"#,
+                "image",
+                "pull",
+            ),
+            (
+                r#"{"status":"start","id":"8c2a","from":"docker.io/library/alpine:latest","Type":"container","Action":"start","Actor":{"ID":"8c2a","Attributes":{"image":"docker.io/library/alpine:latest","name":"test"}},"scope":"local","time":1700000003,"timeNano":1700000003123456789}"#,
+                "container",
+                "start",
+            ),
+            (
+                r#"{"status":"remove","id":"1d2b","Type":"image","Action":"remove","Actor":{"ID":"1d2b","Attributes":{"name":"1d2b"}},"scope":"local","time":1700000004,"timeNano":1700000004123456789}"#,
+                "image",
+                "remove",
+            ),
+        ];
+
+        for (line, r#type, action) in &table {
+            let event = parse_events(line.as_bytes())
+                .next()
+                .unwrap()
+                .map(|event_record| normalize_event(Event::from(event_record)))
+                .unwrap();
+            assert_eq!(event.r#type, *r#type, "{}", line);
+            assert_eq!(event.action, *action, "{}", line);
+        }
+    }
+}
@@ -1,223 +1,501 @@
 use crate::{
-    format::CodeStr,
-    state::{self, State},
-    Settings,
+    backend::{Backend, BuildCacheEntry, Container, DiskUsage, Event, Image, ImageUsage, Layer},
+    containers, duration,
+    format::{timestamp, CodeStr},
+    keep::{protecting_rule, KeepRule},
+    labels::{LabelPolicy, KEEP_LABEL},
+    state::{self, ImageRecord, State, Store},
+    threshold::Threshold,
+    volumes, Settings,
 };
 use byte_unit::Byte;
-use scopeguard::guard;
-use serde::{Deserialize, Serialize};
+use log::Level;
 use std::{
-    collections::HashSet,
-    io::{self, BufRead, BufReader},
-    process::{Command, Stdio},
-    time::{SystemTime, UNIX_EPOCH},
+    collections::{HashMap, HashSet},
+    convert::TryFrom,
+    io, mem,
+    sync::{
+        mpsc::{channel, Sender},
+        Mutex,
+    },
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-// A Docker event (a line of output from `docker events --format '{{json .}}'`)
-#[derive(Deserialize, Serialize, Debug)]
-struct Event {
-    #[serde(rename = "Type")]
-    pub r#type: String,
+// When debouncing events, we vacuum after at most this many quiet windows, even if events keep
+// arriving.
+const MAX_DEBOUNCE_WINDOWS: u32 = 10;
 
-    #[serde(rename = "Action")]
-    pub action: String,
+// A message for the main loop
+enum Message {
+    // An event from the container runtime, or an error if the event stream failed
+    Event(io::Result<Event>),
 
-    #[serde(rename = "Actor")]
-    pub actor: EventActor,
+    // A request to reload the settings
+    Reload,
 
-    pub id: String,
+    // It's time to vacuum, even though nothing happened
+    Wakeup,
 }
 
-// A Docker event actor
-#[derive(Deserialize, Serialize, Debug)]
-struct EventActor {
-    #[serde(rename = "Attributes")]
-    pub attributes: EventActorAttributes,
+// Requests to reload the settings are delivered to the main loop via this. A request which arrives
+// while the main loop is restarting is remembered until it starts listening again.
+#[derive(Default)]
+pub struct ReloadRequests {
+    sender: Option<Sender<Message>>,
+    pending: bool,
 }
 
-// Docker event actor attributes
-#[derive(Deserialize, Serialize, Debug)]
-struct EventActorAttributes {
-    pub image: Option<String>,
+impl ReloadRequests {
+    // Ask the main loop to reload the settings.
+    pub fn request(&mut self) {
+        if let Some(sender) = &self.sender {
+            if sender.send(Message::Reload).is_ok() {
+                return;
+            }
+        }
+
+        self.pending = true;
+    }
+
+    // Start delivering requests to the main loop, including any which arrived in the meantime.
+    fn subscribe(&mut self, sender: Sender<Message>) {
+        if mem::take(&mut self.pending) {
+            // The receiver is still alive, since the main loop just created it.
+            let _ = sender.send(Message::Reload);
+        }
+
+        self.sender = Some(sender);
+    }
+
+    // Stop delivering requests to the main loop.
+    fn unsubscribe(&mut self) {
+        self.sender = None;
+    }
 }
 
-// A line of output from `docker system df --format '{{json .}}'`
-#[derive(Deserialize, Serialize, Debug)]
-struct SpaceRecord {
-    #[serde(rename = "Type")]
-    pub r#type: String,
+// An image which is planned to be deleted
+struct Eviction<'a> {
+    image: &'a Image,
+    last_used: Duration,
+
+    // The space we expect to be freed by this deletion
+    size: Byte,
 
-    #[serde(rename = "Size")]
-    pub size: String,
+    // The space we expect images to use after this deletion
+    projected_space: Byte,
+
+    // If the image is being deleted because it has been idle for too long, this is how long it was
+    // allowed to be idle.
+    max_idle: Option<Duration>,
+}
+
+// Why an image can't be deleted
+pub enum Protection<'a> {
+    // The image has the `docuum.keep` label.
+    Label,
+
+    // The image matches a keep rule.
+    Rule(&'a KeepRule),
+
+    // A running container is using the image.
+    InUse,
 }
 
-// Ask Docker for the ID of an image.
-pub fn image_id(image: &str) -> io::Result<String> {
-    // Query Docker for the image ID.
-    let output = Command::new("docker")
-        .args(&["image", "inspect", "--format", "{{.ID}}", image])
-        .stderr(Stdio::inherit())
-        .output()?;
+// The images ranked by how soon they'd be deleted
+pub struct Ranking<'a> {
+    // The images which may be deleted, from lowest to highest priority and from least recently used
+    // to most recently used within each priority
+    pub candidates: Vec<&'a Image>,
 
-    // Ensure the command succeeded.
-    if !output.status.success() {
-        return Err(io::Error::new(
-            io::ErrorKind::Other,
-            format!("Unable to determine ID of image {}.", image.code_str()),
-        ));
+    // The images which can't be deleted, and why
+    pub protected: Vec<(&'a Image, Protection<'a>)>,
+
+    // The candidates which have been idle for too long, and how long they were allowed to be idle
+    pub expired: HashMap<&'a str, Duration>,
+
+    // When the next candidate will have been idle for too long, if ever, as a duration since the
+    // UNIX epoch
+    pub next_expiry: Option<Duration>,
+}
+
+impl Ranking<'_> {
+    // The candidates in the order they'd be deleted. Candidates which have been idle for too long
+    // are deleted first. Others are only deleted as needed to free space.
+    pub fn eviction_order(&self) -> impl Iterator<Item = &Image> {
+        let expired = move |image: &&Image| self.expired.contains_key(image.id.as_str());
+        self.candidates.iter().copied().filter(expired).chain(
+            self.candidates
+                .iter()
+                .copied()
+                .filter(move |image| !expired(image)),
+        )
     }
+}
 
-    // Interpret the output bytes as UTF-8 and trim any leading/trailing whitespace.
-    String::from_utf8(output.stdout)
-        .map(|output| output.trim().to_owned())
-        .map_err(|error| io::Error::new(io::ErrorKind::Other, error))
+// Ask the container runtime for all the images, keyed by ID.
+pub fn images(backend: &dyn Backend) -> io::Result<HashMap<String, Image>> {
+    Ok(backend
+        .images()?
+        .into_iter()
+        .map(|image| (image.id.clone(), image))
+        .collect())
 }
 
-// Ask Docker for the IDs of all the images.
-pub fn image_ids() -> io::Result<HashSet<String>> {
-    // Query Docker for the image IDs.
-    let output = Command::new("docker")
-        .args(&["image", "ls", "--all", "--no-trunc", "--format", "{{.ID}}"])
-        .stderr(Stdio::inherit())
-        .output()?;
+// Make sure `cache` has the layers of every image and nothing else. Layers never change for a given
+// image ID, so the cache can be reused across wake-ups. If we can't determine the layers of an
+// image, we leave it out of the cache and try again next time.
+fn cache_layers(
+    backend: &dyn Backend,
+    images: &HashMap<String, Image>,
+    cache: &mut HashMap<String, Vec<Layer>>,
+) {
+    // Forget about images which no longer exist.
+    cache.retain(|image_id, _| images.contains_key(image_id));
 
-    // Ensure the command succeeded.
-    if !output.status.success() {
-        return Err(io::Error::new(
-            io::ErrorKind::Other,
-            "Unable to determine IDs of all images.",
-        ));
+    // Look up the layers of any new images.
+    for image_id in images.keys() {
+        if cache.contains_key(image_id) {
+            continue;
+        }
+
+        match backend.image_layers(image_id) {
+            Ok(layers) => {
+                cache.insert(image_id.clone(), layers);
+            }
+            Err(error) => {
+                debug!(
+                    "Unable to determine the layers of image {}. Details: {}",
+                    image_id.code_str(),
+                    error,
+                );
+            }
+        }
     }
+}
 
-    // Interpret the output bytes as UTF-8 and collect the lines.
-    String::from_utf8(output.stdout)
-        .map(|output| {
-            output
-                .lines()
-                .map(|line| line.trim().to_owned())
-                .filter(|line| !line.is_empty())
-                .collect()
-        })
-        .map_err(|error| io::Error::new(io::ErrorKind::Other, error))
-}
-
-// Ask Docker for the IDs of the images currently in use by containers.
-pub fn image_ids_in_use() -> io::Result<HashSet<String>> {
-    // Query Docker for the image IDs.
-    let output = Command::new("docker")
-        .args(&[
-            "container",
-            "ls",
-            "--all",
-            "--no-trunc",
-            "--format",
-            "{{.Image}}",
-        ])
-        .stderr(Stdio::inherit())
-        .output()?;
-
-    // Ensure the command succeeded.
-    if !output.status.success() {
-        return Err(io::Error::new(
-            io::ErrorKind::Other,
-            "Unable to determine IDs of images currently in use by containers.",
-        ));
-    }
-
-    // Interpret the output bytes as UTF-8 and collect the lines.
-    String::from_utf8(output.stdout)
-        .map_err(|error| io::Error::new(io::ErrorKind::Other, error))
-        .map(|output| {
-            output
-                .lines()
-                .filter_map(|line| {
-                    if line.is_empty() {
-                        None
-                    } else {
-                        match image_id(line.trim()) {
-                            Ok(the_image_id) => Some(the_image_id),
-                            Err(error) => {
-                                // This failure may happen if a container was created from an
-                                // image that no longer exists. This is non-fatal, so we just log
-                                // the error and continue.
-                                error!("{}", error);
-                                None
-                            }
-                        }
-                    }
-                })
-                .collect()
+// Determine when each image used by a container was last used, as a duration since the UNIX epoch.
+// Images of running containers are in use now, whereas images of stopped containers were last used
+// when the most recent of those containers stopped, according to `finish_times` (from
+// `containers::finish_times`). Containers which have never run don't count.
+pub fn image_last_uses(
+    containers: &[Container],
+    finish_times: &HashMap<String, Duration>,
+    now: Duration,
+) -> HashMap<String, Duration> {
+    let mut image_uses = HashMap::<String, Duration>::new();
+
+    for container in containers {
+        if container.image_id.is_empty() {
+            continue;
+        }
+
+        let last_used = if container.running() {
+            Some(now)
+        } else {
+            finish_times.get(&container.id).copied()
+        };
+
+        if let Some(last_used) = last_used {
+            let entry = image_uses
+                .entry(container.image_id.clone())
+                .or_insert(last_used);
+            *entry = (*entry).max(last_used);
+        }
+    }
+
+    image_uses
+}
+
+// Determine which images are used by running containers. Those images can't be deleted.
+pub fn images_in_use(containers: &[Container]) -> HashSet<&str> {
+    containers
+        .iter()
+        .filter(|container| container.running())
+        .map(|container| container.image_id.as_str())
+        .collect()
+}
+
+// Delete an image.
+fn delete_image(backend: &dyn Backend, image_id: &str) -> io::Result<()> {
+    info!("Deleting image {}\u{2026}", image_id.code_str());
+    backend.delete_image(image_id)
+}
+
+// Plan which images to delete, given the candidates in the order they should be deleted and the
+// maximum idle time of any candidates which have been idle for too long. Expired candidates are
+// always deleted. Other candidates are deleted only as needed to bring the space usage within the
+// target. Deleting an image only frees the layers which aren't used by any other remaining
+// image, so we walk the layer graph to find the shortest prefix of the remaining candidates which
+// would free enough space. Images in that prefix which wouldn't free any layers (e.g., because they
+// only add metadata on top of a more recently used image) are skipped.
+fn plan_evictions<'a>(
+    candidates: &[&'a Image],
+    expired: &HashMap<&str, Duration>,
+    images: &HashMap<String, Image>,
+    state: &State,
+    usage: &DiskUsage,
+    layer_cache: &HashMap<String, Vec<Layer>>,
+    target: &Byte,
+) -> Vec<Eviction<'a>> {
+    // Determine the layers of each image. For any images which aren't in the cache, we fall back to
+    // a single layer containing the space the image doesn't share with other images.
+    let layers = images
+        .values()
+        .map(|image| {
+            (
+                image.id.as_str(),
+                layer_cache.get(&image.id).cloned().unwrap_or_else(|| {
+                    vec![Layer {
+                        id: image.id.clone(),
+                        size: usage
+                            .images
+                            .get(&image.id)
+                            .map_or_else(|| Byte::from_bytes(0), ImageUsage::unique_size),
+                    }]
+                }),
+            )
         })
+        .collect::<HashMap<_, _>>();
+
+    // Count the images which use each layer.
+    let mut references = HashMap::<&str, usize>::new();
+    for image_layers in layers.values() {
+        for layer in image_layers {
+            *references.entry(&layer.id).or_insert(0) += 1;
+        }
+    }
+
+    // Find the shortest prefix of the candidates (with the expired candidates moved to the front)
+    // which would free enough space, and remember which layers it would free.
+    let mut remaining_references = references.clone();
+    let mut freed = 0_u128;
+    let mut prefix = vec![];
+    for &image in candidates
+        .iter()
+        .filter(|image| expired.contains_key(image.id.as_str()))
+        .chain(
+            candidates
+                .iter()
+                .filter(|image| !expired.contains_key(image.id.as_str())),
+        )
+    {
+        // Stop once we expect to be within the target, unless there are expired images left.
+        if !expired.contains_key(image.id.as_str())
+            && usage.total.get_bytes().saturating_sub(freed) <= target.get_bytes()
+        {
+            break;
+        }
+
+        // The `unwrap`s are safe by the construction of `layers` and `references`.
+        for layer in layers.get(image.id.as_str()).unwrap() {
+            let count = remaining_references.get_mut(layer.id.as_str()).unwrap();
+            *count -= 1;
+            if *count == 0 {
+                freed += layer.size.get_bytes();
+            }
+        }
+
+        prefix.push(image);
+    }
+
+    // Plan to delete the images in the prefix which are expired or would free at least one layer.
+    let mut evictions = vec![];
+    let mut projected_space = usage.total.get_bytes();
+    for image in prefix {
+        // The `unwrap`s are safe by the construction of `layers` and `references`.
+        let max_idle = expired.get(image.id.as_str()).copied();
+        let image_layers = layers.get(image.id.as_str()).unwrap();
+        if max_idle.is_none()
+            && image_layers
+                .iter()
+                .all(|layer| *remaining_references.get(layer.id.as_str()).unwrap() > 0)
+        {
+            debug!(
+                "Skipping image {} since deleting it would not free any layers.",
+                image.id.code_str(),
+            );
+            continue;
+        }
+
+        // Count the layers this deletion would free, given the deletions planned before it.
+        let mut size = 0_u128;
+        for layer in image_layers {
+            let count = references.get_mut(layer.id.as_str()).unwrap();
+            *count -= 1;
+            if *count == 0 {
+                size += layer.size.get_bytes();
+            }
+        }
+        projected_space = projected_space.saturating_sub(size);
+
+        evictions.push(Eviction {
+            image,
+            // The `unwrap` is safe because every image has a record in `state` by now.
+            last_used: state.images.get(&image.id).unwrap().last_used,
+            size: Byte::from_bytes(size),
+            projected_space: Byte::from_bytes(projected_space),
+            max_idle,
+        });
+    }
+
+    evictions
 }
 
-// Get the total space used by Docker images.
-fn space_usage() -> io::Result<Byte> {
-    // Query Docker for the space usage.
-    let output = Command::new("docker")
-        .args(&["system", "df", "--format", "{{json .}}"])
-        .stderr(Stdio::inherit())
-        .output()?;
-
-    // Ensure the command succeeded.
-    if !output.status.success() {
-        return Err(io::Error::new(
-            io::ErrorKind::Other,
-            "Unable to determine the disk space used by Docker images.",
-        ));
-    }
-
-    // Find the relevant line of output.
-    String::from_utf8(output.stdout)
-        .map_err(|error| io::Error::new(io::ErrorKind::Other, error))
-        .and_then(|output| {
-            for line in output.lines() {
-                // Parse the line as a space record.
-                if let Ok(space_record) = serde_json::from_str::<SpaceRecord>(&line) {
-                    // Return early if we found the record we're looking for.
-                    if space_record.r#type == "Images" {
-                        return Byte::from_str(&space_record.size).map_err(|_| {
-                            io::Error::new(
-                                io::ErrorKind::Other,
-                                format!("Invalid threshold {}.", space_record.size.code_str()),
-                            )
-                        });
-                    }
+// Delete the images in an eviction plan. If any deletions fail (e.g., because a container started
+// using the image in the meantime), the space they were expected to free is still in use. So we ask
+// `plan` for a new plan given the images which were deleted, the images which couldn't be deleted,
+// and the space actually used now, until every deletion in the plan succeeds. Each round rules out
+// at least one candidate, so this terminates.
+fn carry_out_evictions<'a>(
+    backend: &dyn Backend,
+    mut evictions: Vec<Eviction<'a>>,
+    plan: impl Fn(&HashSet<String>, &HashSet<String>, &DiskUsage) -> Vec<Eviction<'a>>,
+) -> io::Result<()> {
+    let mut deleted = HashSet::new();
+    let mut failed = HashSet::new();
+
+    loop {
+        let mut all_deleted = true;
+        for eviction in &evictions {
+            if let Some(max_idle) = eviction.max_idle {
+                info!(
+                    "Image {} has been idle for longer than {}.",
+                    eviction.image.id.code_str(),
+                    duration::format(max_idle).code_str(),
+                );
+            }
+
+            // Delete the image and continue.
+            match delete_image(backend, &eviction.image.id) {
+                Ok(()) => {
+                    deleted.insert(eviction.image.id.clone());
+                }
+                Err(error) => {
+                    error!("{}", error);
+                    failed.insert(eviction.image.id.clone());
+                    all_deleted = false;
                 }
             }
+        }
+
+        if all_deleted {
+            return Ok(());
+        }
 
-            Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!(
-                    "Unable to parse output of {}: {}",
-                    "docker system df".code_str(),
-                    output.code_str(),
-                ),
-            ))
+        debug!("Planning again without the images which couldn't be deleted\u{2026}");
+        evictions = plan(&deleted, &failed, &backend.disk_usage()?);
+    }
+}
+
+// Plan which build cache entries to delete to free the given number of bytes, least recently used
+// first. Entries which are in use by a build are skipped.
+fn plan_build_cache_evictions(usage: &DiskUsage, excess: u128) -> Vec<&BuildCacheEntry> {
+    let mut entries = usage
+        .build_cache
+        .iter()
+        .filter(|entry| !entry.in_use)
+        .collect::<Vec<_>>();
+    entries.sort_by_key(|entry| entry.last_used);
+
+    let mut freed = 0_u128;
+    entries
+        .into_iter()
+        .take_while(|entry| {
+            let needed = freed < excess;
+            freed += entry.size.get_bytes();
+            needed
         })
+        .collect()
 }
 
-// Delete a Docker image.
-fn delete_image(image_id: &str) -> io::Result<()> {
-    info!("Deleting image {}\u{2026}", image_id.code_str());
+// Log a plan for the build cache without carrying it out.
+fn report_build_cache_evictions(entries: &[&BuildCacheEntry]) {
+    for entry in entries {
+        info!(
+            "Would delete build cache entry {}, {}, which would free {}.",
+            entry.id.code_str(),
+            entry.last_used.map_or_else(
+                || "never used".to_owned(),
+                |last_used| format!("last used at {}", timestamp(last_used).code_str()),
+            ),
+            entry
+                .size
+                .get_appropriate_unit(false)
+                .to_string()
+                .code_str(),
+        );
+    }
+}
 
-    // Tell Docker to delete the image.
-    let mut child = Command::new("docker")
-        .args(&["image", "rm", "--force", "--no-prune", image_id])
-        .spawn()?;
+// Log an eviction plan without carrying it out.
+fn report_evictions(backend: &dyn Backend, evictions: &[Eviction]) {
+    for eviction in evictions {
+        if let Some(max_idle) = eviction.max_idle {
+            info!(
+                "Image {} has been idle for longer than {}.",
+                eviction.image.id.code_str(),
+                duration::format(max_idle).code_str(),
+            );
+        }
 
-    // Ensure the command succeeded.
-    if !child.wait()?.success() {
-        return Err(io::Error::new(
-            io::ErrorKind::Other,
-            format!("Unable to delete image {}.", image_id.code_str()),
-        ));
+        info!(
+            "Would delete image {} ({}), last used at {}, which would free {}. {} images would \
+             then use about {}.",
+            eviction.image.id.code_str(),
+            if eviction.image.repo_tags.is_empty() {
+                "untagged".to_owned()
+            } else {
+                eviction
+                    .image
+                    .repo_tags
+                    .iter()
+                    .map(|repo_tag| repo_tag.code_str().to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            },
+            timestamp(eviction.last_used).code_str(),
+            eviction
+                .size
+                .get_appropriate_unit(false)
+                .to_string()
+                .code_str(),
+            backend.name(),
+            eviction
+                .projected_space
+                .get_appropriate_unit(false)
+                .to_string()
+                .code_str(),
+        );
     }
+}
 
-    Ok(())
+// Determine the space which counts toward the threshold.
+fn space_used(usage: &DiskUsage, settings: &Settings) -> Byte {
+    if settings.build_cache {
+        Byte::from_bytes(usage.total.get_bytes() + usage.build_cache_total().get_bytes())
+    } else {
+        usage.total
+    }
 }
 
-// Update the timestamp for an image.
-fn update_timestamp(state: &mut State, image_id: &str, verbose: bool) -> io::Result<()> {
+// Describe what counts toward the threshold, for logging.
+fn subject(backend: &dyn Backend, settings: &Settings) -> String {
+    if settings.build_cache {
+        format!("{} images and build cache are", backend.name())
+    } else {
+        format!("{} images are", backend.name())
+    }
+}
+
+// Update the timestamp for an image, recording what caused the use. Returns the record for the image.
+pub fn update_timestamp<'a>(
+    state: &'a mut State,
+    image_id: &str,
+    timestamp: Duration,
+    cause: &str,
+    verbose: bool,
+) -> &'a mut ImageRecord {
     if verbose {
         info!(
             "Updating last-used timestamp for image {}\u{2026}",
@@ -230,26 +508,125 @@ fn update_timestamp(state: &mut State, image_id: &str, verbose: bool) -> io::Res
         );
     }
 
-    match SystemTime::now().duration_since(UNIX_EPOCH) {
-        Ok(duration) => {
-            state.images.insert(image_id.to_owned(), duration);
-            Ok(())
+    let record = state
+        .images
+        .entry(image_id.to_owned())
+        .or_insert_with(|| ImageRecord::new(timestamp));
+    record.last_used = timestamp;
+    record.last_use_cause = Some(cause.to_owned());
+    record
+}
+
+// Get the current time expressed as a duration since the UNIX epoch.
+pub fn now() -> io::Result<Duration> {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(io::Error::other)
+}
+
+// Rank the images by how soon they'd be deleted. Every image must have a record in `state`. Images
+// in `in_use` are protected, since they're used by running containers.
+pub fn rank<'a>(
+    images: &'a HashMap<String, Image>,
+    state: &State,
+    settings: &'a Settings,
+    in_use: &HashSet<&str>,
+    now: Duration,
+) -> Ranking<'a> {
+    // Read the eviction policy of each image from its labels.
+    let policies = images
+        .values()
+        .map(|image| (image.id.as_str(), LabelPolicy::from_image(image)))
+        .collect::<HashMap<_, _>>();
+
+    // Sort the images from lowest to highest priority, and from least recently used to most
+    // recently used within each priority.
+    let mut sorted_images = images.values().collect::<Vec<_>>();
+    sorted_images.sort_by(|&x, &y| {
+        // The `unwrap`s here are safe by the construction of `sorted_images` and `policies`.
+        let x_key = (
+            policies.get(x.id.as_str()).unwrap().priority,
+            state.images.get(&x.id).unwrap().last_used,
+        );
+        let y_key = (
+            policies.get(y.id.as_str()).unwrap().priority,
+            state.images.get(&y.id).unwrap().last_used,
+        );
+        x_key.cmp(&y_key)
+    });
+
+    // Set aside any protected images.
+    let mut candidates = vec![];
+    let mut protected = vec![];
+    for image in sorted_images {
+        // The `unwrap` is safe by the construction of `policies`.
+        if in_use.contains(image.id.as_str()) {
+            protected.push((image, Protection::InUse));
+        } else if policies.get(image.id.as_str()).unwrap().keep {
+            protected.push((image, Protection::Label));
+        } else if let Some(rule) = protecting_rule(&settings.keep, image) {
+            protected.push((image, Protection::Rule(rule)));
+        } else {
+            candidates.push(image);
         }
-        Err(error) => Err(io::Error::new(io::ErrorKind::Other, error)),
+    }
+
+    // Determine how long each candidate may be idle, if there's a limit. Labels take precedence over
+    // the global setting. The `unwrap` is safe by the construction of `policies`.
+    let max_idle = |image: &Image| {
+        policies
+            .get(image.id.as_str())
+            .unwrap()
+            .max_idle
+            .or(settings.max_idle)
+    };
+
+    // Find any candidates which have been idle for too long, and determine when the next one will
+    // be. The `unwrap`s are safe because every image has a record in `state`.
+    let expired = candidates
+        .iter()
+        .filter_map(|image| {
+            max_idle(image)
+                .filter(|max_idle| {
+                    now.saturating_sub(state.images.get(&image.id).unwrap().last_used) > *max_idle
+                })
+                .map(|max_idle| (image.id.as_str(), max_idle))
+        })
+        .collect::<HashMap<_, _>>();
+    let next_expiry = candidates
+        .iter()
+        .filter(|image| !expired.contains_key(image.id.as_str()))
+        .filter_map(|image| {
+            max_idle(image)
+                .map(|max_idle| state.images.get(&image.id).unwrap().last_used + max_idle)
+        })
+        .min();
+
+    Ranking {
+        candidates,
+        protected,
+        expired,
+        next_expiry,
     }
 }
 
-// The main vacuum logic
-fn vacuum(state: &mut State, threshold: &Byte) -> io::Result<()> {
-    // Inform the user that Docuum is receiving events from Docker.
-    info!("Waking up\u{2026}");
+// The result of a vacuum
+pub struct Vacuumed {
+    // When to vacuum again even if nothing happens, if ever, as a duration since the UNIX epoch.
+    // That's when the next image or volume will have been idle for too long, when the next exited
+    // container is due for removal, or when the interval elapses, whichever comes first.
+    pub next_vacuum: Option<Duration>,
 
-    // Determine all the image IDs.
-    let image_ids = image_ids()?;
+    // Whether the space used still exceeds the threshold afterward (in a dry run, whether anything
+    // would have been deleted to bring it within the threshold)
+    pub over_threshold: bool,
+}
 
+// Bring the records in the state in line with the images which exist.
+fn refresh_records(state: &mut State, images: &HashMap<String, Image>, now: Duration) {
     // Remove non-existent images from `state`.
     state.images.retain(|image_id, _| {
-        if image_ids.contains(image_id) {
+        if images.contains_key(image_id) {
             true
         } else {
             debug!(
@@ -260,166 +637,913 @@ fn vacuum(state: &mut State, threshold: &Byte) -> io::Result<()> {
         }
     });
 
-    // In preparation fro the next step, pre-compute the timestamp
-    // corresponding to the current time.
-    let now_timestamp = match SystemTime::now().duration_since(UNIX_EPOCH) {
-        Ok(duration) => Ok(duration),
-        Err(error) => Err(io::Error::new(io::ErrorKind::Other, error)),
-    }?;
+    // Add any missing images to `state`, and refresh the tags of the others.
+    for (image_id, image) in images {
+        state
+            .images
+            .entry(image_id.clone())
+            .or_insert_with(|| {
+                debug!(
+                    "Adding missing record for image {}\u{2026}",
+                    &image_id.code_str(),
+                );
 
-    // Add any missing images to `state`.
-    for image_id in &image_ids {
-        state.images.entry(image_id.clone()).or_insert_with(|| {
-            debug!(
-                "Adding missing record for image {}\u{2026}",
-                &image_id.code_str(),
-            );
+                ImageRecord::new(now)
+            })
+            .repo_tags
+            .clone_from(&image.repo_tags);
+    }
+}
 
-            now_timestamp
-        });
+// Update the timestamps of any images used by containers. Timestamps only move forward, since the
+// image may have been used more recently than its containers stopped (e.g., by a pull).
+fn record_container_uses(
+    state: &mut State,
+    images: &HashMap<String, Image>,
+    containers: &[Container],
+    finish_times: &HashMap<String, Duration>,
+    now: Duration,
+) {
+    for (image_id, last_used) in image_last_uses(containers, finish_times, now) {
+        // Containers can outlive their images (e.g., if the image was force-deleted), so we only
+        // consider images which still exist.
+        if images.contains_key(&image_id)
+            && state
+                .images
+                .get(&image_id)
+                .map_or(true, |record| record.last_used <= last_used)
+        {
+            update_timestamp(state, &image_id, last_used, "container", false);
+        }
     }
+}
 
-    // Update the timestamps of any images in use.
-    for image_id in image_ids_in_use()? {
-        update_timestamp(state, &image_id, false)?;
+// Record the size of each image, and log how much space the build cache is using.
+fn record_usage(backend: &dyn Backend, state: &mut State, settings: &Settings, usage: &DiskUsage) {
+    for (image_id, image_usage) in &usage.images {
+        if let Some(record) = state.images.get_mut(image_id) {
+            record.size = u64::try_from(image_usage.size.get_bytes()).unwrap_or(u64::MAX);
+        }
     }
 
-    // Sort the image IDs from least recently used to most recently used.
-    let mut image_ids_vec = image_ids.iter().collect::<Vec<_>>();
-    image_ids_vec.sort_by(|&x, &y| {
-        // The two `unwrap`s here are safe by the construction of `image_ids_vec`.
-        state
-            .images
-            .get(x)
-            .unwrap()
-            .cmp(state.images.get(y).unwrap())
-    });
+    if !usage.build_cache.is_empty() {
+        log!(
+            if settings.build_cache {
+                Level::Info
+            } else {
+                Level::Debug
+            },
+            "The {} build cache is using {}.",
+            backend.name(),
+            usage
+                .build_cache_total()
+                .get_appropriate_unit(false)
+                .to_string()
+                .code_str(),
+        );
+    }
+}
 
-    // Check if we're over threshold.
-    let space = space_usage()?;
-    if space > *threshold {
-        info!(
-            "Docker images are currently using {} but the limit is {}. Some \
-             images will be deleted.",
-            space.get_appropriate_unit(false).to_string().code_str(),
+// Determine the threshold for the space currently used, and the space to free down to once it's
+// exceeded. Once we're over the threshold, we delete images until we're within the low watermark,
+// so we don't have to delete something after nearly every new image.
+fn resolve_thresholds(
+    backend: &dyn Backend,
+    settings: &Settings,
+    space: Byte,
+) -> io::Result<(Byte, Byte)> {
+    let threshold = settings.threshold.resolve(backend, space)?;
+    if settings.threshold != Threshold::Absolute(threshold) {
+        debug!(
+            "The threshold {} currently allows {}.",
+            settings.threshold.to_string().code_str(),
             threshold.get_appropriate_unit(false).to_string().code_str(),
         );
+    }
 
-        // Start deleting images, starting with the least recently used.
-        for image_id in image_ids_vec {
-            // Break if we're within the threshold.
-            let new_space = space_usage()?;
-            if new_space <= *threshold {
-                info!(
-                    "Docker images are now using {}, which is within the limit of {}.",
-                    new_space.get_appropriate_unit(false).to_string().code_str(),
+    let target = match &settings.low_watermark {
+        Some(low_watermark) => {
+            let target = low_watermark.resolve(backend, space)?;
+            if target > threshold {
+                warn!(
+                    "The low watermark {} exceeds the threshold {}. Using the threshold instead.",
+                    target.get_appropriate_unit(false).to_string().code_str(),
                     threshold.get_appropriate_unit(false).to_string().code_str(),
                 );
-                break;
-            }
-
-            // Delete the image and continue.
-            if let Err(error) = delete_image(image_id) {
-                error!("{}", error);
+                threshold
+            } else {
+                target
             }
         }
+        None => threshold,
+    };
+
+    Ok((threshold, target))
+}
+
+// Log how the space used compares to the threshold.
+fn report_space(backend: &dyn Backend, settings: &Settings, space: Byte, threshold: Byte) {
+    if space > threshold {
+        info!(
+            "{} currently using {} but the limit is {}. Some \
+             {} {}.",
+            subject(backend, settings),
+            space.get_appropriate_unit(false).to_string().code_str(),
+            threshold.get_appropriate_unit(false).to_string().code_str(),
+            if settings.build_cache {
+                "build cache entries or images"
+            } else {
+                "images"
+            },
+            if settings.dry_run {
+                "would be deleted, but this is a dry run"
+            } else {
+                "will be deleted"
+            },
+        );
     } else {
         info!(
-            "Docker images are using {}, which is within the limit of {}.",
+            "{} using {}, which is within the limit of {}.",
+            subject(backend, settings),
             space.get_appropriate_unit(false).to_string().code_str(),
             threshold.get_appropriate_unit(false).to_string().code_str(),
         );
     }
+}
+
+// Log why the protected images won't be deleted. We only mention them at the info level if they
+// might have been deleted otherwise.
+fn report_protections(protected: &[(&Image, Protection)], over_threshold: bool) {
+    let protection_level = if over_threshold {
+        Level::Info
+    } else {
+        Level::Debug
+    };
+
+    for (image, protection) in protected {
+        match protection {
+            Protection::Label => log!(
+                protection_level,
+                "Image {} is protected by its {} label.",
+                image.id.code_str(),
+                KEEP_LABEL.code_str(),
+            ),
+            Protection::Rule(rule) => log!(
+                protection_level,
+                "Image {} is protected by keep rule {}.",
+                image.id.code_str(),
+                rule.to_string().code_str(),
+            ),
+            Protection::InUse => log!(
+                protection_level,
+                "Image {} is in use by a running container.",
+                image.id.code_str(),
+            ),
+        }
+    }
+}
+
+// Decide which build cache entries to delete to bring the space used down to `goal`, if the build
+// cache counts toward the threshold. Also returns how many bytes of build cache would remain, which
+// the images have to fit alongside.
+fn plan_build_cache<'a>(
+    usage: &'a DiskUsage,
+    settings: &Settings,
+    space: Byte,
+    goal: Byte,
+) -> (Vec<&'a BuildCacheEntry>, u128) {
+    if !settings.build_cache {
+        return (vec![], 0);
+    }
+
+    let evictions =
+        plan_build_cache_evictions(usage, space.get_bytes().saturating_sub(goal.get_bytes()));
+    let remaining = usage
+        .build_cache_total()
+        .get_bytes()
+        .saturating_sub(evictions.iter().map(|entry| entry.size.get_bytes()).sum());
+
+    (evictions, remaining)
+}
+
+// Delete the build cache entries in a plan. Failures are only logged, since the images can still be
+// deleted.
+fn delete_build_cache(backend: &dyn Backend, entries: &[&BuildCacheEntry]) {
+    for entry in entries {
+        info!("Deleting build cache entry {}\u{2026}", entry.id.code_str());
+        if let Err(error) = backend.delete_build_cache(&entry.id) {
+            error!("{}", error);
+        }
+    }
+}
+
+// Log how the space used after deleting things compares to the threshold and the low watermark.
+fn report_outcome(
+    backend: &dyn Backend,
+    settings: &Settings,
+    new_space: Byte,
+    threshold: Byte,
+    target: Byte,
+) {
+    if new_space <= target {
+        info!(
+            "{} now using {}, which is within the {} of {}.",
+            subject(backend, settings),
+            new_space.get_appropriate_unit(false).to_string().code_str(),
+            if target == threshold {
+                "limit"
+            } else {
+                "low watermark"
+            },
+            target.get_appropriate_unit(false).to_string().code_str(),
+        );
+    } else if new_space <= threshold {
+        warn!(
+            "{} now using {}, which is within the limit of {} but above the low watermark of {}.",
+            subject(backend, settings),
+            new_space.get_appropriate_unit(false).to_string().code_str(),
+            threshold.get_appropriate_unit(false).to_string().code_str(),
+            target.get_appropriate_unit(false).to_string().code_str(),
+        );
+    } else {
+        warn!(
+            "{} still using {}, which exceeds the limit of {}.",
+            subject(backend, settings),
+            new_space.get_appropriate_unit(false).to_string().code_str(),
+            threshold.get_appropriate_unit(false).to_string().code_str(),
+        );
+    }
+}
+
+// The main vacuum logic
+fn vacuum(
+    backend: &dyn Backend,
+    state: &mut State,
+    store: &mut dyn Store,
+    settings: &Settings,
+    layer_cache: &mut HashMap<String, Vec<Layer>>,
+) -> io::Result<Vacuumed> {
+    // Inform the user that Docuum is receiving events from Docker.
+    info!("Waking up\u{2026}");
+
+    // Keep other processes from changing the state until it's persisted, and pick up any changes
+    // they made in the meantime (e.g., images marked as used with `docuum touch`).
+    let _lock = state::lock(store.path())?;
+    state::sync(state, store);
+
+    // Determine all the images, and make sure each of them has a record.
+    let images = images(backend)?;
+    let now_timestamp = now()?;
+    refresh_records(state, &images, now_timestamp);
+
+    // Remove any containers which exited long enough ago, so they no longer pin their images.
+    let containers = backend.containers()?;
+    let finish_times = containers::finish_times(backend, &containers)?;
+    let (containers, next_container_removal) =
+        containers::remove_exited(backend, settings, containers, &finish_times, now_timestamp);
+    record_container_uses(state, &images, &containers, &finish_times, now_timestamp);
+
+    // Rank the images by how soon they'd be deleted.
+    let ranking = rank(
+        &images,
+        state,
+        settings,
+        &images_in_use(&containers),
+        now_timestamp,
+    );
+
+    // Check if we're over threshold. The build cache counts too, if the user asked for that.
+    let usage = backend.disk_usage()?;
+    let space = space_used(&usage, settings);
+    record_usage(backend, state, settings, &usage);
+    let (threshold, target) = resolve_thresholds(backend, settings, space)?;
+    let over_threshold = space > threshold;
+    let mut still_over_threshold = over_threshold;
+    report_space(backend, settings, space, threshold);
+    report_protections(&ranking.protected, over_threshold);
+    let Ranking {
+        candidates,
+        expired,
+        next_expiry,
+        ..
+    } = ranking;
+
+    if over_threshold || !expired.is_empty() {
+        // Decide which build cache entries to delete, if any. They go before any images.
+        let goal = if over_threshold { target } else { threshold };
+        let (build_cache_evictions, remaining_build_cache) =
+            plan_build_cache(&usage, settings, space, goal);
+
+        // Decide which images to delete. They have to fit in whatever space the remaining build
+        // cache leaves.
+        cache_layers(backend, &images, layer_cache);
+        let image_target = Byte::from_bytes(goal.get_bytes().saturating_sub(remaining_build_cache));
+        let plan = |deleted: &HashSet<String>, failed: &HashSet<String>, usage: &DiskUsage| {
+            let remaining_images = images
+                .iter()
+                .filter(|(image_id, _)| !deleted.contains(*image_id))
+                .map(|(image_id, image)| (image_id.clone(), image.clone()))
+                .collect::<HashMap<_, _>>();
+            let remaining_candidates = candidates
+                .iter()
+                .copied()
+                .filter(|image| !deleted.contains(&image.id) && !failed.contains(&image.id))
+                .collect::<Vec<_>>();
+
+            plan_evictions(
+                &remaining_candidates,
+                &expired,
+                &remaining_images,
+                state,
+                usage,
+                layer_cache,
+                &image_target,
+            )
+        };
+        let evictions = plan(&HashSet::new(), &HashSet::new(), &usage);
+
+        // Report the plan if this is a dry run. Otherwise, carry it out and check how much space we
+        // actually freed.
+        if settings.dry_run {
+            report_build_cache_evictions(&build_cache_evictions);
+            report_evictions(backend, &evictions);
+        } else if !build_cache_evictions.is_empty() || !evictions.is_empty() {
+            delete_build_cache(backend, &build_cache_evictions);
+            carry_out_evictions(backend, evictions, plan)?;
+
+            let new_space = space_used(&backend.disk_usage()?, settings);
+            still_over_threshold = new_space > threshold;
+            report_outcome(backend, settings, new_space, threshold, target);
+        }
+    }
+
+    // Apply the volume policy, if any.
+    let next_volume_expiry = volumes::vacuum(backend, state, settings, &usage, now_timestamp)?;
 
     // Persist the state [tag:vacuum_persists_state].
-    state::save(&state)?;
+    store.save(state)?;
+
+    // Decide when to wake up again if nothing happens in the meantime.
+    if let Some(next_expiry) = next_expiry {
+        debug!(
+            "The next image will become idle for too long at {}.",
+            timestamp(next_expiry).code_str(),
+        );
+    }
+    let next_vacuum = next_expiry
+        .into_iter()
+        .chain(next_volume_expiry)
+        .chain(next_container_removal)
+        .chain(settings.interval.map(|interval| now_timestamp + interval))
+        .min();
+
+    Ok(Vacuumed {
+        next_vacuum,
+        over_threshold: still_over_threshold,
+    })
+}
+
+// Vacuum once without listening for events, e.g., at the end of a CI job. Returns whether the space
+// used still exceeds the threshold afterward.
+pub fn vacuum_once(
+    settings: &Settings,
+    state: &mut State,
+    store: &mut dyn Store,
+) -> io::Result<bool> {
+    let backend = settings.backend.connect()?;
+
+    vacuum(&*backend, state, store, settings, &mut HashMap::new())
+        .map(|vacuumed| vacuumed.over_threshold)
+}
+
+// Replace the settings with reloaded ones, and switch to the new store, if any. The next vacuum
+// saves the state there. Returns whether the container runtime changed.
+fn apply_settings(
+    settings: &mut Settings,
+    store: &mut Box<dyn Store>,
+    new_settings: Settings,
+) -> io::Result<bool> {
+    let backend_changed = new_settings.backend != settings.backend;
+    let store_changed = new_settings.state_format != settings.state_format
+        || new_settings.state_path != settings.state_path;
+    *settings = new_settings;
 
-    // Inform the user that we're done for now.
+    if store_changed {
+        let new_store = settings.state_format.open(settings.state_path.as_deref())?;
+        state::check_writable(new_store.path())?;
+        info!(
+            "Switching to the state file {}\u{2026}",
+            new_store.path().to_string_lossy().code_str(),
+        );
+        *store = new_store;
+    }
+
+    Ok(backend_changed)
+}
+
+// Stream events from the container runtime and vacuum when necessary. Returns `Ok` if the settings
+// were reloaded and the main loop needs to reconnect to a different container runtime.
+pub fn run(
+    settings: &mut Settings,
+    reload: &dyn Fn() -> io::Result<Settings>,
+    state: &mut State,
+    store: &mut Box<dyn Store>,
+    reload_requests: &Mutex<ReloadRequests>,
+) -> io::Result<()> {
+    // Connect to the container runtime.
+    let backend = settings.backend.connect()?;
+
+    // Remember the layers of each image across wake-ups.
+    let mut layer_cache = HashMap::new();
+
+    // Run the main vacuum logic, and remember when to run it again even if nothing happens.
+    let mut next_vacuum =
+        vacuum(&*backend, state, &mut **store, settings, &mut layer_cache)?.next_vacuum;
     info!("Going back to sleep\u{2026}");
 
-    Ok(())
+    // Forward the event stream and any reload requests to a single channel. The event stream blocks,
+    // so it's read on its own thread. That thread stops at the next event once we stop listening.
+    let (sender, receiver) = channel();
+    let events = backend.events()?;
+    let backend_name = backend.name();
+    // The `unwrap`s on this lock are safe because it's never held across a panic.
+    reload_requests.lock().unwrap().subscribe(sender.clone());
+    thread::spawn(move || {
+        for event in events {
+            if sender.send(Message::Event(event)).is_err() {
+                return;
+            }
+        }
+
+        // If we get here, something happened to the event stream.
+        let _ = sender.send(Message::Event(Err(io::Error::other(format!(
+            "The {} event stream unexpectedly terminated.",
+            backend_name,
+        )))));
+    });
+
+    // Handle each incoming message.
+    // If we're waiting for events to quiet down, this is when the first of them arrived and when
+    // to vacuum if nothing else happens.
+    let mut debounced_vacuum: Option<(Duration, Duration)> = None;
+    let result = loop {
+        // Wait for a message, or until it's time to vacuum again. The `unwrap` and the error case
+        // of `recv_timeout` are unreachable because `reload_requests` holds a sender until we're
+        // done.
+        let message = match next_vacuum
+            .into_iter()
+            .chain(debounced_vacuum.map(|(_, deadline)| deadline))
+            .min()
+        {
+            Some(deadline) => match now() {
+                Ok(now) => receiver
+                    .recv_timeout(deadline.saturating_sub(now))
+                    .unwrap_or(Message::Wakeup),
+                Err(error) => break Err(error),
+            },
+            None => receiver.recv().unwrap(),
+        };
+
+        // Decide whether to vacuum now.
+        let outcome = match message {
+            Message::Event(Err(error)) => Err(error),
+            Message::Event(Ok(event)) => match handle_event(&*backend, state, settings, event) {
+                Ok(true) => {
+                    if let Some(debounce) = settings.debounce {
+                        // Wait for the events to quiet down before vacuuming, but not forever.
+                        now().map(|now| {
+                            let first_event =
+                                debounced_vacuum.map_or(now, |(first_event, _)| first_event);
+                            let deadline =
+                                (now + debounce).min(first_event + debounce * MAX_DEBOUNCE_WINDOWS);
+                            debug!(
+                                "Waiting until {} for more events\u{2026}",
+                                timestamp(deadline).code_str(),
+                            );
+                            debounced_vacuum = Some((first_event, deadline));
+                            false
+                        })
+                    } else {
+                        Ok(true)
+                    }
+                }
+                other => other,
+            },
+            Message::Wakeup => Ok(true),
+            Message::Reload => {
+                info!("Reloading the configuration\u{2026}");
+                match reload() {
+                    // Reconnect if the container runtime changed. Otherwise, apply the new settings
+                    // right away.
+                    Ok(new_settings) => match apply_settings(settings, store, new_settings) {
+                        Ok(true) => {
+                            info!("Switching container runtimes\u{2026}");
+                            break Ok(());
+                        }
+                        Ok(false) => Ok(true),
+                        Err(error) => break Err(error),
+                    },
+                    Err(error) => {
+                        error!(
+                            "Unable to reload the configuration. Keeping the current settings. \
+                             Details: {}",
+                            error,
+                        );
+                        Ok(false)
+                    }
+                }
+            }
+        };
+
+        // Run the main vacuum logic if necessary. This also takes care of any debounced events.
+        match outcome.and_then(|vacuum_now| {
+            if vacuum_now {
+                vacuum(&*backend, state, &mut **store, settings, &mut layer_cache).map(Some)
+            } else {
+                Ok(None)
+            }
+        }) {
+            Ok(Some(vacuumed)) => {
+                next_vacuum = vacuumed.next_vacuum;
+                info!("Going back to sleep\u{2026}");
+                debounced_vacuum = None;
+            }
+            Ok(None) => {}
+            Err(error) => break Err(error),
+        }
+    };
+
+    // Stop listening for reload requests until the main loop starts again.
+    reload_requests.lock().unwrap().unsubscribe();
+
+    result
 }
 
-// Stream Docker events and vacuum when necessary.
-pub fn run(settings: &Settings, state: &mut State) -> io::Result<()> {
-    // Run the main vacuum logic.
-    vacuum(state, &settings.threshold)?;
+// Update the timestamp for the image or volume involved in an event, if any. Returns whether the
+// event calls for a vacuum.
+fn handle_event(
+    backend: &dyn Backend,
+    state: &mut State,
+    settings: &Settings,
+    event: Event,
+) -> io::Result<bool> {
+    // Volumes are identified by name, so there's nothing to look up. Using a volume only matters
+    // for the volume policy, if there is one, so it's no reason to vacuum otherwise.
+    if event.r#type == "volume"
+        && (event.action == "create" || event.action == "mount" || event.action == "unmount")
+    {
+        debug!(
+            "Updating last-used timestamp for volume {}\u{2026}",
+            event.actor_id.code_str(),
+        );
+        state.volumes.insert(event.actor_id, now()?);
+        return Ok(volumes::has_policy(settings));
+    }
 
-    // Spawn `docker events --format '{{json .}}'`.
-    let mut child = guard(
-        Command::new("docker")
-            .args(&["events", "--format", "{{json .}}"])
-            .stdout(Stdio::piped())
-            .spawn()?,
-        |mut child| {
-            let _ = child.kill();
-            let _ = child.wait();
+    // Get the ID of the image.
+    let image_id = backend.image_id(
+        &if event.r#type == "container" && event.action == "destroy" {
+            if let Some(image_name) = event.attributes.get("image") {
+                image_name.clone()
+            } else {
+                debug!("Invalid {} event.", backend.name());
+                return Ok(false);
+            }
+        } else if event.r#type == "image"
+            && (event.action == "import"
+                || event.action == "load"
+                || event.action == "pull"
+                || event.action == "push"
+                || event.action == "save"
+                || event.action == "tag")
+        {
+            event.actor_id
+        } else {
+            debug!("Skipping due to irrelevance.");
+            return Ok(false);
         },
-    );
+    )?;
 
-    // Buffer the data as we read it line-by-line.
-    let reader = BufReader::new(child.stdout.as_mut().map_or_else(
-        || {
-            Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!("Unable to read output from {}.", "docker events".code_str()),
-            ))
+    // Update the timestamp for this image. It will be persisted when we vacuum
+    // [ref:vacuum_persists_state].
+    update_timestamp(state, &image_id, now()?, &event.action, true).use_count += 1;
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{plan_evictions, rank, vacuum, Protection};
+    use crate::{
+        backend::{
+            fake::{image, FakeBackend},
+            Backend, DiskUsage, Image, Layer,
         },
-        Ok,
-    )?);
-
-    // Handle each incoming event.
-    for line_option in reader.lines() {
-        // Unwrap the line.
-        let line = line_option?;
-        debug!("Incoming event: {}", line.code_str());
-
-        // Parse the line as an event.
-        let event = match serde_json::from_str::<Event>(&line) {
-            Ok(event) => {
-                debug!("Parsed as: {}", format!("{:?}", event).code_str());
-                event
-            }
-            Err(error) => {
-                debug!("Skipping due to: {}", error);
-                continue;
+        fixtures::{settings, state, TempDir},
+        keep::KeepRule,
+        labels::KEEP_LABEL,
+        state::{State, Store},
+    };
+    use byte_unit::Byte;
+    use std::{
+        collections::{HashMap, HashSet},
+        io,
+        path::{Path, PathBuf},
+        str::FromStr,
+        time::Duration,
+    };
+
+    // A store which keeps the state in memory. Only the lock file touches the disk.
+    struct MemoryStore {
+        // Holds the lock file, and is deleted along with it when the store is dropped
+        _directory: TempDir,
+        path: PathBuf,
+        saved: Option<State>,
+    }
+
+    impl MemoryStore {
+        fn new() -> Self {
+            let directory = TempDir::new();
+            let path = directory.path().join("state.yml");
+            Self {
+                _directory: directory,
+                path,
+                saved: None,
             }
+        }
+    }
+
+    impl Store for MemoryStore {
+        fn path(&self) -> &Path {
+            &self.path
+        }
+
+        fn load(&mut self) -> io::Result<State> {
+            self.saved
+                .clone()
+                .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))
+        }
+
+        fn save(&mut self, state: &State) -> io::Result<()> {
+            self.saved = Some(state.clone());
+            Ok(())
+        }
+
+        fn changed(&self) -> bool {
+            false
+        }
+    }
+
+    fn images(images: &[Image]) -> HashMap<String, Image> {
+        images
+            .iter()
+            .map(|image| (image.id.clone(), image.clone()))
+            .collect()
+    }
+
+    fn ids<'a>(images: impl IntoIterator<Item = &'a Image>) -> Vec<&'a str> {
+        images.into_iter().map(|image| image.id.as_str()).collect()
+    }
+
+    #[test]
+    fn rank_orders_least_recently_used_first() {
+        let images = images(&[image("a"), image("b"), image("c")]);
+        let state = state(&[("a", 1), ("b", 3), ("c", 2)], &[]);
+        let settings = settings(0);
+
+        let ranking = rank(
+            &images,
+            &state,
+            &settings,
+            &HashSet::new(),
+            Duration::from_secs(4),
+        );
+        assert_eq!(ids(ranking.candidates.iter().copied()), vec!["a", "c", "b"]);
+        assert!(ranking.protected.is_empty());
+        assert_eq!(ranking.next_expiry, None);
+    }
+
+    #[test]
+    fn rank_protects_images_in_use_and_kept_images() {
+        let mut labeled = image("b");
+        labeled
+            .labels
+            .insert(KEEP_LABEL.to_owned(), "true".to_owned());
+        let mut tagged = image("c");
+        tagged.repo_tags.push("ci/base:1".to_owned());
+        let images = images(&[image("a"), labeled, tagged, image("d")]);
+        let state = state(&[("a", 1), ("b", 2), ("c", 3), ("d", 4)], &[]);
+        let mut settings = settings(0);
+        settings.keep.push(KeepRule::from_str("ci/base:*").unwrap());
+        let in_use = ["a"].iter().copied().collect();
+
+        let ranking = rank(&images, &state, &settings, &in_use, Duration::from_secs(5));
+        assert_eq!(ids(ranking.candidates.iter().copied()), vec!["d"]);
+        let protections = ranking
+            .protected
+            .iter()
+            .map(|(image, protection)| {
+                (
+                    image.id.as_str(),
+                    match protection {
+                        Protection::InUse => "in use",
+                        Protection::Label => "label",
+                        Protection::Rule(_) => "rule",
+                    },
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            protections,
+            vec![("a", "in use"), ("b", "label"), ("c", "rule")],
+        );
+    }
+
+    #[test]
+    fn rank_expires_idle_images_first() {
+        let images = images(&[image("a"), image("b"), image("c")]);
+        let state = state(&[("a", 50), ("b", 80), ("c", 95)], &[]);
+        let mut settings = settings(0);
+        settings.max_idle = Some(Duration::from_secs(10));
+
+        let ranking = rank(
+            &images,
+            &state,
+            &settings,
+            &HashSet::new(),
+            Duration::from_secs(100),
+        );
+        assert_eq!(ranking.expired.len(), 2);
+        assert_eq!(ranking.expired.get("a"), Some(&Duration::from_secs(10)));
+        assert_eq!(ranking.next_expiry, Some(Duration::from_secs(105)));
+        assert_eq!(ids(ranking.eviction_order()), vec!["a", "b", "c"]);
+    }
+
+    #[test]
+    fn plan_evictions_skips_images_which_free_no_layers() {
+        // `y` and `z` share all their layers, but only `z` is a candidate, so deleting it frees
+        // nothing. `x` shares the base layer with both.
+        let layer = |id: &str, size: u128| Layer {
+            id: id.to_owned(),
+            size: Byte::from_bytes(size),
+        };
+        let layer_cache = vec![
+            ("x".to_owned(), vec![layer("base", 1000), layer("x", 10)]),
+            ("y".to_owned(), vec![layer("base", 1000), layer("y", 10)]),
+            ("z".to_owned(), vec![layer("base", 1000), layer("y", 10)]),
+        ]
+        .into_iter()
+        .collect();
+        let images = images(&[image("x"), image("y"), image("z")]);
+        let state = state(&[("x", 2), ("y", 3), ("z", 1)], &[]);
+        let usage = DiskUsage {
+            total: Byte::from_bytes(1020),
+            images: HashMap::new(),
+            build_cache: vec![],
+            volumes: vec![],
         };
+        let candidates = [&images["z"], &images["x"]];
 
-        // Get the ID of the image.
-        let image_id = image_id(
-            &if event.r#type == "container" && event.action == "destroy" {
-                if let Some(image_name) = event.actor.attributes.image {
-                    image_name
-                } else {
-                    debug!("Invalid Docker event.");
-                    continue;
-                }
-            } else if event.r#type == "image"
-                && (event.action == "import"
-                    || event.action == "load"
-                    || event.action == "pull"
-                    || event.action == "push"
-                    || event.action == "save"
-                    || event.action == "tag")
-            {
-                event.id
-            } else {
-                debug!("Skipping due to irrelevance.");
-                continue;
-            },
-        )?;
+        let evictions = plan_evictions(
+            &candidates,
+            &HashMap::new(),
+            &images,
+            &state,
+            &usage,
+            &layer_cache,
+            &Byte::from_bytes(1015),
+        );
+        assert_eq!(evictions.len(), 1);
+        assert_eq!(evictions[0].image.id, "x");
+        assert_eq!(evictions[0].size, Byte::from_bytes(10));
+        assert_eq!(evictions[0].projected_space, Byte::from_bytes(1010));
+    }
+
+    #[test]
+    fn plan_evictions_always_includes_expired_images() {
+        let images = images(&[image("a"), image("b")]);
+        let state = state(&[("a", 1), ("b", 2)], &[]);
+        let backend = FakeBackend::new(&[("a", 100), ("b", 100)]);
+        let usage = backend.disk_usage().unwrap();
+        let expired = vec![("b", Duration::from_secs(1))].into_iter().collect();
+        let candidates = [&images["a"], &images["b"]];
+
+        let evictions = plan_evictions(
+            &candidates,
+            &expired,
+            &images,
+            &state,
+            &usage,
+            &HashMap::new(),
+            &Byte::from_bytes(1000),
+        );
+        assert_eq!(
+            ids(evictions.iter().map(|eviction| eviction.image)),
+            vec!["b"],
+        );
+        assert_eq!(evictions[0].max_idle, Some(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn vacuum_deletes_least_recently_used_until_within_threshold() {
+        let backend = FakeBackend::new(&[("a", 1000), ("b", 1000), ("c", 1000), ("d", 1000)]);
+        let mut state = state(&[("a", 3), ("b", 1), ("c", 4), ("d", 2)], &[]);
+        let mut store = MemoryStore::new();
+
+        let vacuumed = vacuum(
+            &backend,
+            &mut state,
+            &mut store,
+            &settings(2500),
+            &mut HashMap::new(),
+        )
+        .unwrap();
+        assert_eq!(backend.deleted(), vec!["b", "d"]);
+        assert!(!vacuumed.over_threshold);
+        // The records of the deleted images are removed the next time.
+        let mut recorded = store
+            .saved
+            .as_ref()
+            .unwrap()
+            .images
+            .keys()
+            .collect::<Vec<_>>();
+        recorded.sort();
+        assert_eq!(recorded, vec!["a", "b", "c", "d"]);
+    }
+
+    #[test]
+    fn vacuum_never_deletes_images_in_use() {
+        let backend = FakeBackend::new(&[("a", 1000), ("b", 1000), ("c", 1000), ("d", 1000)]);
+        backend.add_container("running", "a", "running");
+        let mut state = state(&[("a", 1), ("b", 2), ("c", 3), ("d", 4)], &[]);
+
+        let vacuumed = vacuum(
+            &backend,
+            &mut state,
+            &mut MemoryStore::new(),
+            &settings(500),
+            &mut HashMap::new(),
+        )
+        .unwrap();
+        // Even deleting everything else isn't enough, but the image in use stays.
+        assert_eq!(backend.deleted(), vec!["b", "c", "d"]);
+        assert!(vacuumed.over_threshold);
+    }
 
-        // Update the timestamp for this image.
-        update_timestamp(state, &image_id, true)?;
+    #[test]
+    fn vacuum_plans_again_when_a_deletion_fails() {
+        let mut backend = FakeBackend::new(&[("a", 1000), ("b", 1000), ("c", 1000), ("d", 1000)]);
+        backend.undeletable.insert("a".to_owned());
+        let mut state = state(&[("a", 1), ("b", 2), ("c", 3), ("d", 4)], &[]);
 
-        // Run the main vacuum logic. This will also persist the state [ref:vacuum_persists_state].
-        vacuum(state, &settings.threshold)?;
+        let vacuumed = vacuum(
+            &backend,
+            &mut state,
+            &mut MemoryStore::new(),
+            &settings(2500),
+            &mut HashMap::new(),
+        )
+        .unwrap();
+        assert_eq!(backend.deleted(), vec!["b", "c"]);
+        assert!(!vacuumed.over_threshold);
     }
 
-    // The `for` loop above will only terminate if something happened to `docker events`.
-    Err(io::Error::new(
-        io::ErrorKind::Other,
-        format!("{} unexpectedly terminated.", "docker events".code_str()),
-    ))
+    #[test]
+    fn vacuum_reports_when_nothing_more_can_be_deleted() {
+        let mut backend = FakeBackend::new(&[("a", 1000), ("b", 1000)]);
+        backend.undeletable.insert("a".to_owned());
+        backend.undeletable.insert("b".to_owned());
+        let mut state = state(&[("a", 1), ("b", 2)], &[]);
+
+        let vacuumed = vacuum(
+            &backend,
+            &mut state,
+            &mut MemoryStore::new(),
+            &settings(500),
+            &mut HashMap::new(),
+        )
+        .unwrap();
+        assert!(backend.deleted().is_empty());
+        assert!(vacuumed.over_threshold);
+    }
+
+    #[test]
+    fn vacuum_deletes_nothing_in_a_dry_run() {
+        let backend = FakeBackend::new(&[("a", 1000), ("b", 1000)]);
+        let mut state = state(&[("a", 1), ("b", 2)], &[]);
+        let mut settings = settings(500);
+        settings.dry_run = true;
+
+        let vacuumed = vacuum(
+            &backend,
+            &mut state,
+            &mut MemoryStore::new(),
+            &settings,
+            &mut HashMap::new(),
+        )
+        .unwrap();
+        assert!(backend.deleted().is_empty());
+        assert!(vacuumed.over_threshold);
+    }
 }
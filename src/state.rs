@@ -1,86 +1,741 @@
-use crate::format::CodeStr;
+use crate::{format::CodeStr, state_log::LogStore};
+use fs2::FileExt;
 use serde::{Deserialize, Serialize};
+use serde_yaml::Value;
 use std::{
-    collections::HashMap,
-    fs::{create_dir_all, read_to_string, write},
-    io,
-    path::PathBuf,
-    time::Duration,
+    collections::{hash_map::Entry, HashMap},
+    fs::{create_dir_all, metadata, read_to_string, remove_file, rename, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, SystemTime},
 };
 
+// The version of the state file format written by this version of the program. Files without a
+// version predate versioning and are migrated when loaded.
+pub const VERSION: u32 = 1;
+
+// The state file is written to a temporary file with this suffix and then moved into place.
+const TEMP_SUFFIX: &str = ".tmp";
+
+// The previous state file is kept with this suffix, in case the current one is corrupt.
+const BACKUP_SUFFIX: &str = ".bak";
+
+// Processes which change the state lock a file with this suffix next to the state file.
+const LOCK_SUFFIX: &str = ".lock";
+
+// What is known about an image
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ImageRecord {
+    // When the image was first seen, expressed as a duration since the UNIX epoch
+    pub first_seen: Duration,
+
+    // When the image was last used, expressed as a duration since the UNIX epoch
+    pub last_used: Duration,
+
+    // The number of events which have used the image
+    #[serde(default)]
+    pub use_count: u64,
+
+    // The repository tags of the image when it was last seen
+    #[serde(default)]
+    pub repo_tags: Vec<String>,
+
+    // The size of the image in bytes when it was last seen
+    #[serde(default)]
+    pub size: u64,
+
+    // What caused the last use: the event (e.g., `pull`), or `container` if a container was using
+    // the image
+    #[serde(default)]
+    pub last_use_cause: Option<String>,
+}
+
+impl ImageRecord {
+    // Create a record for an image which was just seen for the first time.
+    pub fn new(timestamp: Duration) -> Self {
+        Self {
+            first_seen: timestamp,
+            last_used: timestamp,
+            use_count: 0,
+            repo_tags: vec![],
+            size: 0,
+            last_use_cause: None,
+        }
+    }
+}
+
 // The program state
-#[derive(Deserialize, Serialize)]
+#[derive(Clone, Deserialize, Serialize)]
 #[serde(deny_unknown_fields)]
 pub struct State {
-    // Map from image ID to last use time expressed as a duration since the UNIX epoch
-    pub images: HashMap<String, Duration>,
+    // The version of the state file format
+    pub version: u32,
+
+    // Map from image ID to what is known about the image
+    pub images: HashMap<String, ImageRecord>,
+
+    // Map from volume name to last use time expressed as a duration since the UNIX epoch
+    #[serde(default)]
+    pub volumes: HashMap<String, Duration>,
+}
+
+impl State {
+    // Combine another version of the state into this one, e.g., one saved by another process.
+    // Timestamps only move forward. Use counts can't be added up without knowing which uses the two
+    // versions have in common, so the larger count is kept.
+    pub fn merge(&mut self, other: Self) {
+        for (image_id, other_record) in other.images {
+            match self.images.entry(image_id) {
+                Entry::Occupied(mut entry) => {
+                    let record = entry.get_mut();
+                    record.first_seen = record.first_seen.min(other_record.first_seen);
+                    record.use_count = record.use_count.max(other_record.use_count);
+                    if other_record.last_used > record.last_used {
+                        record.last_used = other_record.last_used;
+                        record.last_use_cause = other_record.last_use_cause;
+                    }
+                }
+                Entry::Vacant(entry) => {
+                    entry.insert(other_record);
+                }
+            }
+        }
+
+        for (volume, other_last_used) in other.volumes {
+            let last_used = self.volumes.entry(volume).or_insert(other_last_used);
+            *last_used = (*last_used).max(other_last_used);
+        }
+    }
+}
+
+// The program state as persisted before the state file format was versioned
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LegacyState {
+    images: HashMap<String, Duration>,
+
+    #[serde(default)]
+    volumes: HashMap<String, Duration>,
+}
+
+impl From<LegacyState> for State {
+    // The legacy format only recorded when each image was last used, so that's also the best guess
+    // for when it was first seen.
+    fn from(legacy: LegacyState) -> Self {
+        Self {
+            version: VERSION,
+            images: legacy
+                .images
+                .into_iter()
+                .map(|(image_id, last_used)| (image_id, ImageRecord::new(last_used)))
+                .collect(),
+            volumes: legacy.volumes,
+        }
+    }
+}
+
+// A place where the program state is persisted
+pub trait Store {
+    // The file containing the state, for logging
+    fn path(&self) -> &Path;
+
+    // Load the program state.
+    fn load(&mut self) -> io::Result<State>;
+
+    // Persist the program state.
+    fn save(&mut self, state: &State) -> io::Result<()>;
+
+    // Whether the state may have been changed by another process since it was last loaded or saved
+    fn changed(&self) -> bool;
+}
+
+// When a file was last modified and how big it is, or `None` if it doesn't exist. Every save
+// changes at least one of those, so comparing fingerprints tells whether a file needs to be read
+// again. Stores take the fingerprint before reading a file, so a change made while reading is
+// picked up next time rather than missed.
+pub type Fingerprint = Option<(SystemTime, u64)>;
+
+pub fn fingerprint(path: &Path) -> Fingerprint {
+    metadata(path)
+        .and_then(|metadata| Ok((metadata.modified()?, metadata.len())))
+        .ok()
+}
+
+// The supported ways to persist the program state
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum StateFormat {
+    // A YAML file which is rewritten in full every time
+    Yaml,
+
+    // An append-only log of changes which is compacted from time to time
+    Log,
+}
+
+impl StateFormat {
+    // The names accepted by `from_str`, for the command-line interface
+    pub const NAMES: &'static [&'static str] = &["yaml", "log"];
+
+    // Open the store for this format, at the configured path or the default one.
+    pub fn open(self, configured_path: Option<&Path>) -> io::Result<Box<dyn Store>> {
+        Ok(match self {
+            Self::Yaml => Box::new(YamlStore::new(path(configured_path, "state.yml")?)),
+            Self::Log => {
+                // A YAML state file can't double as the log. If the path names one (e.g., because
+                // `DOCUUM_STATE` was set with the YAML format in mind), the log goes next to it, and
+                // the YAML file is imported if there's no log yet.
+                let path = path(configured_path, "state.log")?;
+                let is_yaml = path
+                    .extension()
+                    .is_some_and(|extension| extension == "yml" || extension == "yaml");
+                Box::new(if is_yaml {
+                    LogStore::new(path.with_extension("log"), path)
+                } else {
+                    let import_path = path.with_extension("yml");
+                    LogStore::new(path, import_path)
+                })
+            }
+        })
+    }
+}
+
+impl FromStr for StateFormat {
+    type Err = io::Error;
+
+    fn from_str(name: &str) -> io::Result<Self> {
+        match name {
+            "yaml" => Ok(Self::Yaml),
+            "log" => Ok(Self::Log),
+            _ => Err(io::Error::other(format!(
+                "Unknown state format {}.",
+                name.code_str(),
+            ))),
+        }
+    }
 }
 
-// Where the program state is persisted on disk
-fn path() -> Option<PathBuf> {
-    // [tag:state_path_has_parent]
-    dirs::data_local_dir().map(|path| path.join("docuum/state.yml"))
+// Where the program state is persisted on disk by default, if there's a data directory
+fn default_path(file_name: &str) -> Option<PathBuf> {
+    dirs::data_local_dir().map(|path| path.join("docuum").join(file_name))
+}
+
+// Where the program state is persisted on disk, unless another path is configured
+fn path(configured: Option<&Path>, file_name: &str) -> io::Result<PathBuf> {
+    configured
+        .map(ToOwned::to_owned)
+        .or_else(|| default_path(file_name))
+        .ok_or_else(|| {
+            io::Error::other(format!(
+                "Unable to locate data directory. Use {} or {} to choose where to store the state.",
+                "--state-path".code_str(),
+                "DOCUUM_STATE".code_str(),
+            ))
+        })
 }
 
 // Return the state in which the program starts, if no state was loaded from disk.
 pub fn initial() -> State {
     State {
+        version: VERSION,
         images: HashMap::new(),
+        volumes: HashMap::new(),
     }
 }
 
-// Load the program state from disk.
-pub fn load() -> io::Result<State> {
-    // Check if we have a path.
-    if let Some(path) = path() {
-        // Log what we are trying to do in case an error occurs.
-        debug!(
-            "Attempting to load the state from {}\u{2026}",
-            path.to_string_lossy().code_str(),
-        );
+// Load the program state from a store. If there's no state there yet but there is a state file at
+// the default path, import that one. Otherwise, choosing a new path (e.g., by upgrading to a Docker
+// image which sets `DOCUUM_STATE`) would silently lose the history of which images were used when.
+pub fn load(store: &mut dyn Store) -> io::Result<State> {
+    match store.load() {
+        Err(error) if error.kind() == io::ErrorKind::NotFound => {
+            match default_path("state.yml")
+                .filter(|legacy_path| legacy_path != store.path() && legacy_path.exists())
+            {
+                Some(legacy_path) => {
+                    warn!(
+                        "There is no state at {}. Importing the state from {}\u{2026}",
+                        store.path().to_string_lossy().code_str(),
+                        legacy_path.to_string_lossy().code_str(),
+                    );
 
-        // Read the YAML from disk.
-        let yaml = read_to_string(path)?;
+                    YamlStore::new(legacy_path).load()
+                }
+                None => Err(error),
+            }
+        }
+        result => result,
+    }
+}
 
-        // Deserialize the YAML.
-        serde_yaml::from_str(&yaml).map_err(|error| io::Error::new(io::ErrorKind::Other, error))
-    } else {
-        // Fail if we don't have a path.
-        Err(io::Error::new(
-            io::ErrorKind::Other,
-            "Unable to locate data directory.",
-        ))
+// Pick up changes which other processes (e.g., `docuum touch`) saved since the state was loaded. The
+// caller should hold the lock on the state file. The state is only read again if the file changed,
+// since reading it is expensive when there are many images.
+pub fn sync(state: &mut State, store: &mut dyn Store) {
+    if !store.changed() {
+        return;
     }
+
+    match store.load() {
+        Ok(persisted) => state.merge(persisted),
+        // There's nothing to pick up if the state hasn't been saved yet.
+        Err(error) if error.kind() == io::ErrorKind::NotFound => {}
+        // The state will be overwritten when it's saved, so there's no need to give up.
+        Err(error) => warn!("{}", error),
+    }
+}
+
+// Lock the state file so other processes don't change it in the meantime, waiting for them if
+// necessary. The lock is released when the returned file is dropped.
+pub fn lock(path: &Path) -> io::Result<File> {
+    let lock_path = with_suffix(path, LOCK_SUFFIX);
+    create_parent(path)
+        .and_then(|()| {
+            OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(&lock_path)
+        })
+        .and_then(|file| file.lock_exclusive().map(|()| file))
+        .map_err(|error| {
+            io::Error::other(format!(
+                "Unable to lock the state file {}. Details: {}",
+                path.to_string_lossy().code_str(),
+                error,
+            ))
+        })
+}
+
+// Append a suffix to the file name of a path, e.g., to get `state.yml.bak` from `state.yml`.
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut file_name = path.file_name().map(ToOwned::to_owned).unwrap_or_default();
+    file_name.push(suffix);
+    path.with_file_name(file_name)
+}
+
+// The program state persisted as a YAML file
+pub struct YamlStore {
+    path: PathBuf,
+
+    // The fingerprint of the state file as of the last load or save
+    fingerprint: Fingerprint,
+
+    // Whether the last load had to recover from the backup because the state file was corrupt
+    recovered: bool,
 }
 
-// Save the program state to disk.
-pub fn save(state: &State) -> io::Result<()> {
-    // Check if we have a path.
-    if let Some(path) = path() {
+impl YamlStore {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            fingerprint: None,
+            recovered: false,
+        }
+    }
+}
+
+impl Store for YamlStore {
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // If the state file is missing or corrupt, fall back to the backup of the previous state.
+    fn load(&mut self) -> io::Result<State> {
+        self.fingerprint = fingerprint(&self.path);
+        self.recovered = false;
+
+        match read(&self.path) {
+            Ok(state) => Ok(state),
+            Err(error) => {
+                // Give up if there's no backup, e.g., because this is the first run.
+                let backup_path = with_suffix(&self.path, BACKUP_SUFFIX);
+                if !backup_path.exists() {
+                    return Err(error);
+                }
+
+                warn!("{}", error);
+                warn!(
+                    "Recovering the state from the backup {}\u{2026}",
+                    backup_path.to_string_lossy().code_str(),
+                );
+
+                let state = read(&backup_path)?;
+                self.recovered = true;
+                Ok(state)
+            }
+        }
+    }
+
+    fn save(&mut self, state: &State) -> io::Result<()> {
         // Log what we are trying to do in case an error occurs.
         debug!(
             "Persisting the state to {}\u{2026}",
-            path.to_string_lossy().code_str(),
+            self.path.to_string_lossy().code_str(),
         );
 
-        // The `unwrap` is safe due to [ref:state_path_has_parent].
-        let parent = path.parent().unwrap().to_owned();
-
         // The `unwrap` is safe because serialization should never fail.
         let payload = serde_yaml::to_string(state).unwrap();
 
-        // Create the ancestor directories, if needed.
-        create_dir_all(parent)?;
+        // Keep the previous state file as a backup, unless it's the corrupt file we recovered from.
+        // Otherwise, it would replace the good backup.
+        replace(&self.path, payload.as_bytes(), !self.recovered).map_err(|error| {
+            io::Error::other(format!(
+                "Unable to save the state to {}. Details: {}",
+                self.path.to_string_lossy().code_str(),
+                error,
+            ))
+        })?;
+
+        self.fingerprint = fingerprint(&self.path);
+        self.recovered = false;
+
+        Ok(())
+    }
+
+    fn changed(&self) -> bool {
+        fingerprint(&self.path) != self.fingerprint
+    }
+}
+
+// Read the program state from a YAML file.
+fn read(path: &Path) -> io::Result<State> {
+    // Log what we are trying to do in case an error occurs.
+    debug!(
+        "Attempting to load the state from {}\u{2026}",
+        path.to_string_lossy().code_str(),
+    );
 
-        // Write to the file.
-        write(path, payload.as_bytes())?;
+    // Read the YAML from disk and deserialize it.
+    read_to_string(path)
+        .and_then(|yaml| parse(&yaml))
+        .map_err(|error| {
+            io::Error::new(
+                error.kind(),
+                format!(
+                    "Unable to load the state from {}. Details: {}",
+                    path.to_string_lossy().code_str(),
+                    error,
+                ),
+            )
+        })
+}
+
+// Deserialize the program state, migrating it from an older format if needed.
+fn parse(yaml: &str) -> io::Result<State> {
+    // Determine the version of the format before committing to a schema.
+    let value = serde_yaml::from_str::<Value>(yaml).map_err(io::Error::other)?;
+    let version = match value.get("version") {
+        Some(version) => version
+            .as_u64()
+            .ok_or_else(|| io::Error::other("The state file version must be a number."))?,
+        None => 0,
+    };
+
+    match version {
+        0 => {
+            debug!("Migrating the state from the unversioned format\u{2026}");
+            serde_yaml::from_value::<LegacyState>(value)
+                .map(State::from)
+                .map_err(io::Error::other)
+        }
+        version => {
+            check_version(version)?;
+            serde_yaml::from_value(value).map_err(io::Error::other)
+        }
+    }
+}
+
+// Make sure this version of the program understands the given version of the state file format.
+pub fn check_version(version: u64) -> io::Result<()> {
+    if version == u64::from(VERSION) {
+        Ok(())
     } else {
-        // Fail if we don't have a path.
-        return Err(io::Error::new(
-            io::ErrorKind::Other,
-            "Unable to locate data directory.",
-        ));
+        Err(io::Error::other(format!(
+            "The state file has version {}, which this version of Docuum doesn't support.",
+            version.to_string().code_str(),
+        )))
+    }
+}
+
+// Write a file and wait for its contents to reach the disk.
+fn write_synced(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut file = File::create(path)?;
+    file.write_all(contents)?;
+    file.sync_all()
+}
+
+// Wait for the renames in the directory containing a path to reach the disk.
+#[cfg(unix)]
+fn sync_parent(path: &Path) -> io::Result<()> {
+    match path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        Some(parent) => File::open(parent)?.sync_all(),
+        None => File::open(".")?.sync_all(),
+    }
+}
+
+// Directories can't be opened as files on other platforms, so there's nothing to do there.
+#[cfg(not(unix))]
+fn sync_parent(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+// Create the ancestor directories of a path, if needed. A relative path may have an empty parent,
+// which refers to the current directory.
+fn create_parent(path: &Path) -> io::Result<()> {
+    if let Some(parent) = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        create_dir_all(parent)?;
     }
 
     Ok(())
 }
+
+// Replace the contents of a file. The contents are written to a temporary file and flushed to disk,
+// then moved into place. That way, a crash or a full disk never leaves a partially written file
+// behind. Optionally, the previous file is kept as a backup.
+pub fn replace(path: &Path, contents: &[u8], backup: bool) -> io::Result<()> {
+    let temp_path = with_suffix(path, TEMP_SUFFIX);
+    create_parent(path)?;
+    write_synced(&temp_path, contents).inspect_err(|_| {
+        let _ = remove_file(&temp_path);
+    })?;
+    if backup && path.exists() {
+        rename(path, with_suffix(path, BACKUP_SUFFIX))?;
+    }
+    rename(&temp_path, path)?;
+    sync_parent(path)
+}
+
+// Make sure the state can be saved, so a misconfigured path is reported right away rather than
+// after the first vacuum.
+pub fn check_writable(path: &Path) -> io::Result<()> {
+    // Try to create the temporary file which is used when saving.
+    let temp_path = with_suffix(path, TEMP_SUFFIX);
+    create_parent(path)
+        .and_then(|()| File::create(&temp_path))
+        .and_then(|_| remove_file(&temp_path))
+        .map_err(|error| {
+            io::Error::other(format!(
+                "Unable to write the state to {}. Details: {}",
+                path.to_string_lossy().code_str(),
+                error,
+            ))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, sync, ImageRecord, Store, YamlStore, VERSION};
+    use crate::{
+        fixtures::{record, state, TempDir},
+        state_log::LogStore,
+    };
+    use std::{
+        fs::{read_to_string, write},
+        time::Duration,
+    };
+
+    // A record of an image with the given timestamps in seconds after the UNIX epoch, use count,
+    // and cause of the last use
+    fn used(first_seen: u64, last_used: u64, use_count: u64, cause: &str) -> ImageRecord {
+        ImageRecord {
+            first_seen: Duration::from_secs(first_seen),
+            last_used: Duration::from_secs(last_used),
+            use_count,
+            repo_tags: vec![],
+            size: 0,
+            last_use_cause: Some(cause.to_owned()),
+        }
+    }
+
+    #[test]
+    fn parse_migrates_unversioned_state() {
+        let state = parse(
+            "---\n\
+             images:\n  \
+               \"sha256:abc\":\n    \
+                 secs: 100\n    \
+                 nanos: 5\n\
+             volumes:\n  \
+               data:\n    \
+                 secs: 200\n    \
+                 nanos: 0\n",
+        )
+        .unwrap();
+
+        assert_eq!(state.version, VERSION);
+        let record = state.images.get("sha256:abc").unwrap();
+        assert_eq!(record.first_seen, Duration::new(100, 5));
+        assert_eq!(record.last_used, Duration::new(100, 5));
+        assert_eq!(record.use_count, 0);
+        assert_eq!(state.volumes.get("data"), Some(&Duration::from_secs(200)));
+    }
+
+    #[test]
+    fn parse_migrates_unversioned_state_without_volumes() {
+        let state = parse("images: {}\n").unwrap();
+
+        assert_eq!(state.version, VERSION);
+        assert!(state.images.is_empty());
+        assert!(state.volumes.is_empty());
+    }
+
+    #[test]
+    fn parse_reads_current_state() {
+        let state = parse(
+            "version: 1\n\
+             images:\n  \
+               \"sha256:abc\":\n    \
+                 first_seen: {secs: 100, nanos: 0}\n    \
+                 last_used: {secs: 300, nanos: 0}\n    \
+                 use_count: 2\n    \
+                 repo_tags: [\"app:1\"]\n    \
+                 size: 1000\n    \
+                 last_use_cause: pull\n",
+        )
+        .unwrap();
+
+        let record = state.images.get("sha256:abc").unwrap();
+        assert_eq!(record.first_seen, Duration::from_secs(100));
+        assert_eq!(record.last_used, Duration::from_secs(300));
+        assert_eq!(record.use_count, 2);
+        assert_eq!(record.repo_tags, vec!["app:1".to_owned()]);
+        assert_eq!(record.last_use_cause.as_deref(), Some("pull"));
+    }
+
+    #[test]
+    fn parse_rejects_unknown_versions() {
+        assert!(parse("version: 2\nimages: {}\n").is_err());
+        assert!(parse("version: one\nimages: {}\n").is_err());
+    }
+
+    #[test]
+    fn parse_rejects_unknown_fields() {
+        assert!(parse("images: {}\nextra: 1\n").is_err());
+        assert!(parse("version: 1\nimages: {}\nextra: 1\n").is_err());
+    }
+
+    #[test]
+    fn merge_keeps_latest_use_and_earliest_sighting() {
+        let mut ours = state(&[], &[("v", 5), ("w", 9)]);
+        ours.images.insert("a".to_owned(), used(10, 50, 3, "pull"));
+        ours.images.insert("b".to_owned(), used(10, 20, 1, "pull"));
+        let mut theirs = state(&[], &[("v", 7), ("w", 8)]);
+        theirs
+            .images
+            .insert("a".to_owned(), used(5, 40, 4, "touch"));
+        theirs
+            .images
+            .insert("b".to_owned(), used(20, 30, 0, "touch"));
+
+        ours.merge(theirs);
+        assert_eq!(ours.images.get("a"), Some(&used(5, 50, 4, "pull")));
+        assert_eq!(ours.images.get("b"), Some(&used(10, 30, 1, "touch")));
+        assert_eq!(ours.volumes.get("v"), Some(&Duration::from_secs(7)));
+        assert_eq!(ours.volumes.get("w"), Some(&Duration::from_secs(9)));
+    }
+
+    #[test]
+    fn merge_adds_missing_records() {
+        let mut ours = state(&[("a", 1)], &[]);
+        let theirs = state(&[("b", 2)], &[("v", 3)]);
+
+        ours.merge(theirs);
+        assert_eq!(ours.images.len(), 2);
+        assert_eq!(ours.images.get("b"), Some(&record(2)));
+        assert_eq!(ours.volumes.get("v"), Some(&Duration::from_secs(3)));
+    }
+
+    #[test]
+    fn save_after_recovery_keeps_the_backup() {
+        let directory = TempDir::new();
+        let path = directory.path().join("state.yml");
+        let backup_path = directory.path().join("state.yml.bak");
+        let mut store = YamlStore::new(path.clone());
+
+        // Saving twice leaves the first state in the backup.
+        let good_state = state(&[("a", 1)], &[]);
+        store.save(&good_state).unwrap();
+        store.save(&state(&[("a", 1), ("b", 2)], &[])).unwrap();
+        let backup = read_to_string(&backup_path).unwrap();
+
+        write(&path, "not: [valid").unwrap();
+        assert_eq!(store.load().unwrap().images, good_state.images);
+
+        // The corrupt state file must not replace the backup.
+        store.save(&state(&[("a", 1), ("c", 3)], &[])).unwrap();
+        assert_eq!(read_to_string(&backup_path).unwrap(), backup);
+
+        // Once the state file is intact again, saving rotates the backup as usual.
+        let latest = read_to_string(&path).unwrap();
+        store.load().unwrap();
+        store.save(&good_state).unwrap();
+        assert_eq!(read_to_string(&backup_path).unwrap(), latest);
+    }
+
+    #[test]
+    fn sync_picks_up_changes_saved_by_others() {
+        let directory = TempDir::new();
+        let path = directory.path().join("state.yml");
+        let mut daemon_store = YamlStore::new(path.clone());
+        let mut other_store = YamlStore::new(path);
+
+        // Nothing has changed since the daemon saved the state.
+        let mut daemon_state = state(&[("a", 1)], &[]);
+        daemon_store.save(&daemon_state).unwrap();
+        assert!(!daemon_store.changed());
+
+        // Another process marks an image as used.
+        let mut other_state = other_store.load().unwrap();
+        assert!(!other_store.changed());
+        other_state
+            .images
+            .insert("b".to_owned(), used(3, 4, 1, "touch"));
+        other_store.save(&other_state).unwrap();
+        assert!(daemon_store.changed());
+
+        sync(&mut daemon_state, &mut daemon_store);
+        assert_eq!(daemon_state.images.get("a"), Some(&record(1)));
+        assert_eq!(daemon_state.images.get("b"), Some(&used(3, 4, 1, "touch")));
+        assert!(!daemon_store.changed());
+    }
+
+    #[test]
+    fn sync_rewrites_a_log_torn_by_another_process() {
+        let directory = TempDir::new();
+        let path = directory.path().join("state.log");
+        let import_path = directory.path().join("state.yml");
+        let mut daemon_store = LogStore::new(path.clone(), import_path.clone());
+        let mut other_store = LogStore::new(path.clone(), import_path);
+
+        let mut daemon_state = state(&[("a", 1)], &[]);
+        daemon_store.save(&daemon_state).unwrap();
+
+        // Another process marks an image as used, then crashes while appending another change.
+        let mut other_state = other_store.load().unwrap();
+        other_state
+            .images
+            .insert("b".to_owned(), used(3, 4, 1, "touch"));
+        other_store.save(&other_state).unwrap();
+        let mut log = read_to_string(&path).unwrap();
+        log.push_str("{\"op\":\"image\",\"id\":\"c\",\"rec");
+        write(&path, log).unwrap();
+
+        sync(&mut daemon_state, &mut daemon_store);
+        assert_eq!(daemon_state.images.get("b"), Some(&used(3, 4, 1, "touch")));
+
+        // The daemon doesn't append after the partial entry.
+        daemon_state.images.insert("d".to_owned(), record(5));
+        daemon_store.save(&daemon_state).unwrap();
+        let reloaded = LogStore::new(path, directory.path().join("state.yml"))
+            .load()
+            .unwrap();
+        assert_eq!(reloaded.images, daemon_state.images);
+    }
+}
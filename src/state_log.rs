@@ -0,0 +1,496 @@
+use crate::{
+    format::CodeStr,
+    state::{self, Fingerprint, ImageRecord, State, Store, YamlStore},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{read_to_string, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+// The log is compacted once it has this many times as many entries as there are records, so the
+// log stays proportional to the state rather than growing with every change.
+const COMPACTION_RATIO: usize = 4;
+
+// Small logs aren't worth compacting.
+const MIN_COMPACTION_ENTRIES: usize = 1000;
+
+// An entry in the log. Each line of the log is one entry encoded as JSON, and the state is what
+// results from applying the entries in order.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "op", rename_all = "kebab-case")]
+enum Entry {
+    // The first entry of the log, which identifies the version of the format
+    Header { version: u32 },
+
+    // An image was added or its record changed.
+    Image { id: String, record: ImageRecord },
+
+    // An image no longer exists.
+    RemoveImage { id: String },
+
+    // A volume was added or used.
+    Volume { name: String, last_used: Duration },
+
+    // A volume no longer exists.
+    RemoveVolume { name: String },
+}
+
+impl Entry {
+    // Apply this entry to the state.
+    fn apply(self, state: &mut State) {
+        match self {
+            Self::Header { .. } => {}
+            Self::Image { id, record } => {
+                state.images.insert(id, record);
+            }
+            Self::RemoveImage { id } => {
+                state.images.remove(&id);
+            }
+            Self::Volume { name, last_used } => {
+                state.volumes.insert(name, last_used);
+            }
+            Self::RemoveVolume { name } => {
+                state.volumes.remove(&name);
+            }
+        }
+    }
+}
+
+// Determine the entries which turn one state into another.
+fn diff(old: &State, new: &State) -> Vec<Entry> {
+    let mut entries = vec![];
+
+    for (id, record) in &new.images {
+        if old.images.get(id) != Some(record) {
+            entries.push(Entry::Image {
+                id: id.clone(),
+                record: record.clone(),
+            });
+        }
+    }
+
+    for id in old.images.keys() {
+        if !new.images.contains_key(id) {
+            entries.push(Entry::RemoveImage { id: id.clone() });
+        }
+    }
+
+    for (name, last_used) in &new.volumes {
+        if old.volumes.get(name) != Some(last_used) {
+            entries.push(Entry::Volume {
+                name: name.clone(),
+                last_used: *last_used,
+            });
+        }
+    }
+
+    for name in old.volumes.keys() {
+        if !new.volumes.contains_key(name) {
+            entries.push(Entry::RemoveVolume { name: name.clone() });
+        }
+    }
+
+    entries
+}
+
+// Encode entries as lines of JSON.
+fn encode(entries: &[Entry]) -> String {
+    entries
+        .iter()
+        // The `unwrap` is safe because serialization should never fail.
+        .map(|entry| serde_json::to_string(entry).unwrap() + "\n")
+        .collect()
+}
+
+// The program state persisted as an append-only log of changes. Saving only appends the records
+// which changed since the last save, which is much cheaper than rewriting the whole state when
+// there are many images.
+pub struct LogStore {
+    path: PathBuf,
+
+    // A YAML state file which is imported if the log doesn't exist yet
+    import_path: PathBuf,
+
+    // The state as of the end of the log, if the log is known to be intact
+    persisted: Option<State>,
+
+    // The number of entries in the log
+    entries: usize,
+
+    // The fingerprint of the log as of the last load or save
+    fingerprint: Fingerprint,
+}
+
+impl LogStore {
+    pub fn new(path: PathBuf, import_path: PathBuf) -> Self {
+        Self {
+            path,
+            import_path,
+            persisted: None,
+            entries: 0,
+            fingerprint: None,
+        }
+    }
+
+    // Replace the log with the minimal set of entries which produce the state. Optionally, the
+    // previous log is kept as a backup.
+    fn compact(&mut self, state: &State, backup: bool) -> io::Result<()> {
+        debug!(
+            "Compacting the state log {}\u{2026}",
+            self.path.to_string_lossy().code_str(),
+        );
+
+        let mut entries = vec![Entry::Header {
+            version: state::VERSION,
+        }];
+        entries.extend(diff(&state::initial(), state));
+
+        state::replace(&self.path, encode(&entries).as_bytes(), backup)?;
+        self.entries = entries.len();
+
+        Ok(())
+    }
+
+    // Add entries to the end of the log, and wait for them to reach the disk.
+    fn append(&mut self, entries: &[Entry]) -> io::Result<()> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+
+        let mut file = OpenOptions::new().append(true).open(&self.path)?;
+        file.write_all(encode(entries).as_bytes())?;
+        file.sync_data()?;
+        self.entries += entries.len();
+
+        Ok(())
+    }
+
+    // Read the state from the log. Also returns whether the log ended cleanly, in which case it's
+    // safe to append to it.
+    fn read(&mut self) -> io::Result<(State, bool)> {
+        let log = read_to_string(&self.path)?;
+        let lines = log.lines().collect::<Vec<_>>();
+        let mut state = state::initial();
+        let mut intact = log.ends_with('\n');
+
+        for (index, line) in lines.iter().enumerate() {
+            let entry = match serde_json::from_str::<Entry>(line) {
+                Ok(entry) => entry,
+                // A crash while appending can leave a partial entry at the end, which is safe to
+                // ignore since the entries before it are intact.
+                Err(error) if index + 1 == lines.len() && index > 0 => {
+                    warn!(
+                        "Ignoring a partially written entry at the end of the state log. \
+                         Details: {}",
+                        error,
+                    );
+                    intact = false;
+                    break;
+                }
+                Err(error) => {
+                    return Err(io::Error::other(format!(
+                        "Invalid entry on line {}. Details: {}",
+                        (index + 1).to_string().code_str(),
+                        error,
+                    )));
+                }
+            };
+
+            match (index, &entry) {
+                (0, Entry::Header { version }) => state::check_version(u64::from(*version))?,
+                (0, _) => return Err(io::Error::other("The state log has no header.")),
+                _ => {}
+            }
+
+            entry.apply(&mut state);
+        }
+
+        if lines.is_empty() {
+            return Err(io::Error::other("The state log is empty."));
+        }
+
+        self.entries = lines.len();
+
+        Ok((state, intact))
+    }
+}
+
+impl Store for LogStore {
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn load(&mut self) -> io::Result<State> {
+        self.fingerprint = state::fingerprint(&self.path);
+
+        // Forget what an earlier load found, since the log may have changed since then. Until it's
+        // known to be intact again, it's replaced rather than appended to.
+        self.persisted = None;
+        self.entries = 0;
+
+        // Migrate from a YAML state file if there's no log yet. The log is created when the state
+        // is first saved.
+        if !self.path.exists() && self.import_path.exists() {
+            info!(
+                "Importing the state from {}\u{2026}",
+                self.import_path.to_string_lossy().code_str(),
+            );
+
+            return YamlStore::new(self.import_path.clone()).load();
+        }
+
+        // Log what we are trying to do in case an error occurs.
+        debug!(
+            "Attempting to load the state from {}\u{2026}",
+            self.path.to_string_lossy().code_str(),
+        );
+
+        let (state, intact) = self.read().map_err(|error| {
+            io::Error::new(
+                error.kind(),
+                format!(
+                    "Unable to load the state from {}. Details: {}",
+                    self.path.to_string_lossy().code_str(),
+                    error,
+                ),
+            )
+        })?;
+
+        // A log which didn't end cleanly is replaced rather than appended to when the state is next
+        // saved.
+        if intact {
+            self.persisted = Some(state.clone());
+        }
+
+        Ok(state)
+    }
+
+    fn save(&mut self, state: &State) -> io::Result<()> {
+        // Log what we are trying to do in case an error occurs.
+        debug!(
+            "Persisting the state to {}\u{2026}",
+            self.path.to_string_lossy().code_str(),
+        );
+
+        // Start a new log if the existing one wasn't loaded (e.g., because it's corrupt) or if it has
+        // grown too long. Otherwise, just append the changes. If anything goes wrong, the log may
+        // no longer match `persisted`, so we leave it unset to start over next time. A log which
+        // wasn't loaded is kept as a backup, since it may have history we couldn't read.
+        let result = match self.persisted.take() {
+            Some(mut persisted) => {
+                let entries = diff(&persisted, state);
+                let records = state.images.len() + state.volumes.len();
+
+                if self.entries + entries.len()
+                    > MIN_COMPACTION_ENTRIES.max(COMPACTION_RATIO * records)
+                {
+                    self.compact(state, false)
+                        .map(|()| self.persisted = Some(state.clone()))
+                } else {
+                    self.append(&entries).map(|()| {
+                        for entry in entries {
+                            entry.apply(&mut persisted);
+                        }
+
+                        self.persisted = Some(persisted);
+                    })
+                }
+            }
+            None => self
+                .compact(state, true)
+                .map(|()| self.persisted = Some(state.clone())),
+        };
+
+        result.map_err(|error| {
+            io::Error::other(format!(
+                "Unable to save the state to {}. Details: {}",
+                self.path.to_string_lossy().code_str(),
+                error,
+            ))
+        })?;
+
+        self.fingerprint = state::fingerprint(&self.path);
+
+        Ok(())
+    }
+
+    fn changed(&self) -> bool {
+        state::fingerprint(&self.path) != self.fingerprint
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{diff, Entry, LogStore};
+    use crate::{
+        fixtures::{state, TempDir},
+        state::{State, Store, YamlStore},
+    };
+    use std::fs::{read_to_string, write};
+
+    // A log in the directory, which imports `state.yml` from the same directory
+    fn log_store(directory: &TempDir) -> LogStore {
+        LogStore::new(
+            directory.path().join("state.log"),
+            directory.path().join("state.yml"),
+        )
+    }
+
+    fn assert_same(actual: &State, expected: &State) {
+        assert_eq!(actual.version, expected.version);
+        assert_eq!(actual.images, expected.images);
+        assert_eq!(actual.volumes, expected.volumes);
+    }
+
+    #[test]
+    fn diff_of_equal_states_is_empty() {
+        let state = state(&[("a", 1)], &[("v", 2)]);
+
+        assert!(diff(&state, &state.clone()).is_empty());
+    }
+
+    #[test]
+    fn diff_applied_to_old_state_gives_new_state() {
+        let old = state(&[("a", 1), ("b", 2)], &[("v", 3), ("w", 4)]);
+        let new = state(&[("a", 5), ("c", 6)], &[("v", 7), ("x", 8)]);
+
+        let entries = diff(&old, &new);
+        assert_eq!(entries.len(), 6);
+        assert!(entries
+            .iter()
+            .any(|entry| matches!(entry, Entry::RemoveImage { id } if id == "b")));
+        assert!(entries
+            .iter()
+            .any(|entry| matches!(entry, Entry::RemoveVolume { name } if name == "w")));
+
+        let mut patched = old;
+        for entry in entries {
+            entry.apply(&mut patched);
+        }
+        assert_same(&patched, &new);
+    }
+
+    #[test]
+    fn saved_changes_are_appended_and_loaded() {
+        let directory = TempDir::new();
+        let first = state(&[("a", 1), ("b", 2)], &[]);
+        let second = state(&[("a", 3)], &[("v", 4)]);
+
+        let mut store = log_store(&directory);
+        store.save(&first).unwrap();
+        store.save(&second).unwrap();
+
+        // A header and two images, then an update, a removal, and a volume
+        let log = read_to_string(directory.path().join("state.log")).unwrap();
+        assert_eq!(log.lines().count(), 6);
+        assert_same(&log_store(&directory).load().unwrap(), &second);
+    }
+
+    #[test]
+    fn partially_written_last_entry_is_ignored() {
+        let directory = TempDir::new();
+        log_store(&directory)
+            .save(&state(&[("a", 1)], &[]))
+            .unwrap();
+
+        // Simulate a crash in the middle of appending an entry.
+        let path = directory.path().join("state.log");
+        let mut log = read_to_string(&path).unwrap();
+        log.push_str("{\"op\":\"image\",\"id\":\"b\",\"rec");
+        write(&path, log).unwrap();
+
+        let mut store = log_store(&directory);
+        let loaded = store.load().unwrap();
+        assert_same(&loaded, &state(&[("a", 1)], &[]));
+
+        // The log isn't appended to after the partial entry. It's rewritten instead.
+        let updated = state(&[("a", 1), ("c", 2)], &[]);
+        store.save(&updated).unwrap();
+        assert!(read_to_string(&path)
+            .unwrap()
+            .lines()
+            .all(|line| serde_json::from_str::<Entry>(line).is_ok()));
+        assert_same(&log_store(&directory).load().unwrap(), &updated);
+    }
+
+    #[test]
+    fn log_torn_after_an_intact_load_is_rewritten() {
+        let directory = TempDir::new();
+        let mut store = log_store(&directory);
+        store.save(&state(&[("a", 1)], &[])).unwrap();
+        store.save(&state(&[("a", 1), ("b", 2)], &[])).unwrap();
+        store.load().unwrap();
+
+        // Cut the last entry in half, as if another process crashed while appending it.
+        let path = directory.path().join("state.log");
+        let log = read_to_string(&path).unwrap();
+        write(&path, &log[..log.len() - 10]).unwrap();
+
+        let loaded = store.load().unwrap();
+        assert_same(&loaded, &state(&[("a", 1)], &[]));
+
+        let updated = state(&[("a", 1), ("c", 3)], &[]);
+        store.save(&updated).unwrap();
+        assert_same(&log_store(&directory).load().unwrap(), &updated);
+    }
+
+    #[test]
+    fn corrupt_entry_before_the_end_is_an_error() {
+        let directory = TempDir::new();
+        write(
+            directory.path().join("state.log"),
+            "{\"op\":\"header\",\"version\":1}\n\
+             not json\n\
+             {\"op\":\"remove-image\",\"id\":\"a\"}\n",
+        )
+        .unwrap();
+
+        assert!(log_store(&directory).load().is_err());
+    }
+
+    #[test]
+    fn log_without_header_is_an_error() {
+        let directory = TempDir::new();
+        write(
+            directory.path().join("state.log"),
+            "{\"op\":\"remove-image\",\"id\":\"a\"}\n",
+        )
+        .unwrap();
+
+        assert!(log_store(&directory).load().is_err());
+    }
+
+    #[test]
+    fn unreadable_log_is_backed_up_before_being_replaced() {
+        let directory = TempDir::new();
+        let path = directory.path().join("state.log");
+        write(&path, "garbage\n").unwrap();
+
+        let mut store = log_store(&directory);
+        assert!(store.load().is_err());
+        store.save(&state(&[("a", 1)], &[])).unwrap();
+
+        assert_eq!(
+            read_to_string(directory.path().join("state.log.bak")).unwrap(),
+            "garbage\n",
+        );
+        assert_same(
+            &log_store(&directory).load().unwrap(),
+            &state(&[("a", 1)], &[]),
+        );
+    }
+
+    #[test]
+    fn yaml_state_is_imported_if_there_is_no_log() {
+        let directory = TempDir::new();
+        let imported = state(&[("a", 1)], &[("v", 2)]);
+        YamlStore::new(directory.path().join("state.yml"))
+            .save(&imported)
+            .unwrap();
+
+        assert_same(&log_store(&directory).load().unwrap(), &imported);
+    }
+}
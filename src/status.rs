@@ -0,0 +1,251 @@
+use crate::{
+    containers, duration,
+    format::{timestamp, CodeStr},
+    labels::KEEP_LABEL,
+    run::{image_last_uses, images, images_in_use, now, rank, Protection},
+    state::{self, ImageRecord},
+    Settings,
+};
+use byte_unit::Byte;
+use serde::Serialize;
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryFrom,
+    io,
+    str::FromStr,
+    time::Duration,
+};
+
+// How to print the status
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum OutputFormat {
+    // A table for humans
+    Table,
+
+    // JSON for programs
+    Json,
+}
+
+impl OutputFormat {
+    // The names accepted by `from_str`, for the command-line interface
+    pub const NAMES: &'static [&'static str] = &["table", "json"];
+}
+
+impl FromStr for OutputFormat {
+    type Err = io::Error;
+
+    fn from_str(name: &str) -> io::Result<Self> {
+        match name {
+            "table" => Ok(Self::Table),
+            "json" => Ok(Self::Json),
+            _ => Err(io::Error::other(format!(
+                "Unknown output format {}.",
+                name.code_str(),
+            ))),
+        }
+    }
+}
+
+// The status of an image
+#[derive(Debug, Serialize)]
+struct ImageStatus {
+    id: String,
+    repo_tags: Vec<String>,
+
+    // The size of the image in bytes
+    size: u64,
+
+    // When the image was last used as seconds since the UNIX epoch, if it's known
+    last_used: Option<u64>,
+
+    // Whether a running container is using the image
+    in_use: bool,
+
+    // Why the image can't be deleted, if it can't
+    protected_by: Option<String>,
+
+    // The position of the image in the order in which images would be deleted, starting at 1, if it
+    // can be deleted
+    rank: Option<usize>,
+
+    // Whether the image has been idle for too long and would be deleted regardless of the threshold
+    expired: bool,
+}
+
+// Print rows as a table with left-aligned columns.
+fn print_table(rows: &[Vec<String>]) {
+    let widths = (0..rows[0].len())
+        .map(|column| {
+            rows.iter()
+                .map(|row| row[column].chars().count())
+                .max()
+                .unwrap_or(0)
+        })
+        .collect::<Vec<_>>();
+
+    for row in rows {
+        let line = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect::<Vec<_>>()
+            .join("  ");
+        println!("{}", line.trim_end());
+    }
+}
+
+// Print the statuses of the images as a table for humans.
+fn print_statuses(statuses: &[ImageStatus], now: Duration) {
+    let mut rows = vec![[
+        "RANK",
+        "IMAGE",
+        "TAGS",
+        "SIZE",
+        "LAST USED",
+        "IN USE",
+        "PROTECTED BY",
+    ]
+    .iter()
+    .map(ToString::to_string)
+    .collect::<Vec<_>>()];
+
+    for status in statuses {
+        rows.push(vec![
+            status.rank.map_or_else(
+                || "-".to_owned(),
+                |rank| {
+                    if status.expired {
+                        format!("{} (idle)", rank)
+                    } else {
+                        rank.to_string()
+                    }
+                },
+            ),
+            status
+                .id
+                .trim_start_matches("sha256:")
+                .chars()
+                .take(12)
+                .collect(),
+            if status.repo_tags.is_empty() {
+                "<none>".to_owned()
+            } else {
+                status.repo_tags.join(", ")
+            },
+            Byte::from_bytes(u128::from(status.size))
+                .get_appropriate_unit(false)
+                .to_string(),
+            status.last_used.map_or_else(
+                || "unknown".to_owned(),
+                |last_used| {
+                    let last_used = Duration::from_secs(last_used);
+                    format!(
+                        "{} ago ({})",
+                        duration::format_approximate(now.saturating_sub(last_used)),
+                        timestamp(last_used),
+                    )
+                },
+            ),
+            if status.in_use { "yes" } else { "no" }.to_owned(),
+            status
+                .protected_by
+                .clone()
+                .unwrap_or_else(|| "-".to_owned()),
+        ]);
+    }
+
+    print_table(&rows);
+}
+
+// Print every image along with what Docuum knows about it and how soon it would be deleted. This
+// doesn't change anything.
+pub fn status(settings: &Settings, format: OutputFormat) -> io::Result<()> {
+    // Load the state. It's fine if there isn't any yet.
+    let mut store = settings.state_format.open(settings.state_path.as_deref())?;
+    let mut state = match state::load(&mut *store) {
+        Ok(state) => state,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => state::initial(),
+        Err(error) => return Err(error),
+    };
+
+    // Ask the container runtime about the images and the containers using them.
+    let backend = settings.backend.connect()?;
+    let images = images(&*backend)?;
+    let containers = backend.containers()?;
+    let usage = backend.disk_usage()?;
+    let now = now()?;
+
+    // Fill in the state the way the next vacuum would, without persisting it. We keep track of which
+    // images have a known last-use time, as opposed to one made up for ranking.
+    let mut known = state.images.keys().cloned().collect::<HashSet<_>>();
+    for image_id in images.keys() {
+        state
+            .images
+            .entry(image_id.clone())
+            .or_insert_with(|| ImageRecord::new(now));
+    }
+    let finish_times = containers::finish_times(&*backend, &containers)?;
+    for (image_id, last_used) in image_last_uses(&containers, &finish_times, now) {
+        if let Some(record) = state.images.get_mut(&image_id) {
+            record.last_used = record.last_used.max(last_used);
+            known.insert(image_id);
+        }
+    }
+    let in_use = images_in_use(&containers);
+
+    // Rank the images.
+    let ranking = rank(&images, &state, settings, &in_use, now);
+    let ranks = ranking
+        .eviction_order()
+        .enumerate()
+        .map(|(index, image)| (image.id.as_str(), index + 1))
+        .collect::<HashMap<_, _>>();
+    let protections = ranking
+        .protected
+        .iter()
+        .map(|(image, protection)| {
+            (
+                image.id.as_str(),
+                match protection {
+                    Protection::Label => format!("label {}=true", KEEP_LABEL),
+                    Protection::Rule(rule) => format!("keep rule {}", rule),
+                    Protection::InUse => "running container".to_owned(),
+                },
+            )
+        })
+        .collect::<HashMap<_, _>>();
+
+    // Describe each image, with the next image to be deleted first and protected images last.
+    let mut statuses = images
+        .values()
+        .map(|image| ImageStatus {
+            id: image.id.clone(),
+            repo_tags: image.repo_tags.clone(),
+            size: usage.images.get(&image.id).map_or(0, |image_usage| {
+                u64::try_from(image_usage.size.get_bytes()).unwrap_or(u64::MAX)
+            }),
+            // The `unwrap` is safe because every image has a record by now.
+            last_used: if known.contains(&image.id) {
+                Some(state.images.get(&image.id).unwrap().last_used.as_secs())
+            } else {
+                None
+            },
+            in_use: in_use.contains(image.id.as_str()),
+            protected_by: protections.get(image.id.as_str()).cloned(),
+            rank: ranks.get(image.id.as_str()).copied(),
+            expired: ranking.expired.contains_key(image.id.as_str()),
+        })
+        .collect::<Vec<_>>();
+    statuses
+        .sort_by(|x, y| (x.rank.is_none(), x.rank, &x.id).cmp(&(y.rank.is_none(), y.rank, &y.id)));
+
+    match format {
+        OutputFormat::Json => {
+            // The `unwrap` is safe because serialization should never fail.
+            println!("{}", serde_json::to_string_pretty(&statuses).unwrap());
+        }
+        OutputFormat::Table => print_statuses(&statuses, now),
+    }
+
+    Ok(())
+}
@@ -0,0 +1,278 @@
+use crate::{backend::Backend, format::CodeStr};
+use byte_unit::Byte;
+use std::{
+    fmt, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+// The suffix which indicates an amount of space to keep free
+const FREE_SUFFIX: &str = "free";
+
+// The number of basis points in 100%
+const BASIS_POINTS_PER_WHOLE: u128 = 10_000;
+
+// Parse a percentage (e.g., `12.5`) as a number of basis points. Precision beyond a basis point
+// doesn't matter here, so any further decimal places are ignored.
+fn parse_basis_points(percentage: &str) -> Option<u32> {
+    let (whole, fraction) = percentage.split_once('.').unwrap_or((percentage, ""));
+    if (whole.is_empty() && fraction.is_empty())
+        || !whole
+            .chars()
+            .chain(fraction.chars())
+            .all(|c| c.is_ascii_digit())
+    {
+        return None;
+    }
+
+    let whole = if whole.is_empty() {
+        0
+    } else {
+        whole.parse::<u32>().ok()?
+    };
+    let fraction = format!("{:0<2}", &fraction[..fraction.len().min(2)])
+        .parse::<u32>()
+        .ok()?;
+    whole.checked_mul(100)?.checked_add(fraction)
+}
+
+// A limit on the space used by images
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Threshold {
+    // An amount of space, e.g., `10 GB`
+    Absolute(Byte),
+
+    // A percentage of the capacity of the filesystem containing the images, e.g., `80%`, in basis
+    // points (hundredths of a percent) so it can be applied with integer arithmetic
+    Percentage(u32),
+
+    // An amount of space to keep free on the filesystem containing the images, e.g., `20 GB free`
+    Free(Byte),
+}
+
+impl Threshold {
+    // Determine how much space images may use. Relative thresholds are computed from the
+    // filesystem containing the container runtime's data, given how much space images use now.
+    pub fn resolve(&self, backend: &dyn Backend, space: Byte) -> io::Result<Byte> {
+        match self {
+            Self::Absolute(threshold) => Ok(*threshold),
+            Self::Percentage(basis_points) => {
+                let root_dir = visible_root_dir(backend)?;
+                let capacity =
+                    fs2::total_space(&root_dir).map_err(|error| space_error(&root_dir, &error))?;
+
+                // The product can't overflow, since the capacity fits in 64 bits and the basis
+                // points fit in 32 bits.
+                Ok(Byte::from_bytes(
+                    u128::from(capacity) * u128::from(*basis_points) / BASIS_POINTS_PER_WHOLE,
+                ))
+            }
+            Self::Free(free) => {
+                let root_dir = visible_root_dir(backend)?;
+                let available = fs2::available_space(&root_dir)
+                    .map_err(|error| space_error(&root_dir, &error))?;
+
+                // Images may grow into the available space until only `free` is left.
+                Ok(Byte::from_bytes(
+                    (space.get_bytes() + u128::from(available)).saturating_sub(free.get_bytes()),
+                ))
+            }
+        }
+    }
+}
+
+impl FromStr for Threshold {
+    type Err = io::Error;
+
+    fn from_str(threshold: &str) -> io::Result<Self> {
+        let invalid = || {
+            io::Error::other(format!(
+                "Invalid threshold {}. Expected an amount of space (e.g., {}), a percentage of the \
+                 filesystem capacity (e.g., {}), or an amount of space to keep free (e.g., {}).",
+                threshold.code_str(),
+                "10 GB".code_str(),
+                "80%".code_str(),
+                "20 GB free".code_str(),
+            ))
+        };
+
+        let trimmed = threshold.trim();
+        if let Some(percentage) = trimmed.strip_suffix('%') {
+            let basis_points = parse_basis_points(percentage.trim()).ok_or_else(invalid)?;
+            if u128::from(basis_points) > BASIS_POINTS_PER_WHOLE {
+                return Err(invalid());
+            }
+
+            Ok(Self::Percentage(basis_points))
+        } else if let Some(free) = trimmed.strip_suffix(FREE_SUFFIX) {
+            Byte::from_str(free.trim())
+                .map(Self::Free)
+                .map_err(|_| invalid())
+        } else {
+            Byte::from_str(trimmed)
+                .map(Self::Absolute)
+                .map_err(|_| invalid())
+        }
+    }
+}
+
+impl fmt::Display for Threshold {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Absolute(threshold) => write!(f, "{}", threshold.get_appropriate_unit(false)),
+            Self::Percentage(basis_points) => {
+                let fraction = format!("{:02}", basis_points % 100);
+                let fraction = fraction.trim_end_matches('0');
+                if fraction.is_empty() {
+                    write!(f, "{}%", basis_points / 100)
+                } else {
+                    write!(f, "{}.{}%", basis_points / 100, fraction)
+                }
+            }
+            Self::Free(free) => {
+                write!(f, "{} {}", free.get_appropriate_unit(false), FREE_SUFFIX)
+            }
+        }
+    }
+}
+
+// Get the root directory of the container runtime. It's reported as seen by the daemon, so it only
+// exists here if Docuum runs on the same host or the directory is mounted at the same path.
+fn visible_root_dir(backend: &dyn Backend) -> io::Result<PathBuf> {
+    let root_dir = backend.root_dir()?;
+    if root_dir.exists() {
+        Ok(root_dir)
+    } else {
+        Err(io::Error::other(format!(
+            "The root directory of the container runtime, {}, doesn't exist here. To use a \
+             percentage or an amount of space to keep free as the threshold from within a \
+             container, mount that directory into the container at the same path.",
+            root_dir.to_string_lossy().code_str(),
+        )))
+    }
+}
+
+// Explain that we couldn't query the filesystem containing a path.
+fn space_error(path: &Path, error: &io::Error) -> io::Error {
+    io::Error::other(format!(
+        "Unable to determine the space on the filesystem containing {}. Details: {}",
+        path.to_string_lossy().code_str(),
+        error,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Threshold;
+    use crate::backend::fake::FakeBackend;
+    use byte_unit::Byte;
+    use std::{env, str::FromStr};
+
+    #[test]
+    fn from_str_absolute() {
+        assert_eq!(
+            Threshold::from_str("10 GB").unwrap(),
+            Threshold::Absolute(Byte::from_bytes(10_000_000_000)),
+        );
+        assert_eq!(
+            Threshold::from_str(" 512MiB ").unwrap(),
+            Threshold::Absolute(Byte::from_bytes(512 * 1024 * 1024)),
+        );
+    }
+
+    #[test]
+    fn from_str_percentage() {
+        assert_eq!(
+            Threshold::from_str("80%").unwrap(),
+            Threshold::Percentage(8000),
+        );
+        assert_eq!(
+            Threshold::from_str("12.5 %").unwrap(),
+            Threshold::Percentage(1250),
+        );
+        assert_eq!(
+            Threshold::from_str(".5%").unwrap(),
+            Threshold::Percentage(50),
+        );
+        assert_eq!(
+            Threshold::from_str("1.234%").unwrap(),
+            Threshold::Percentage(123),
+        );
+        assert_eq!(
+            Threshold::from_str("100%").unwrap(),
+            Threshold::Percentage(10_000),
+        );
+    }
+
+    #[test]
+    fn from_str_free() {
+        assert_eq!(
+            Threshold::from_str("20 GB free").unwrap(),
+            Threshold::Free(Byte::from_bytes(20_000_000_000)),
+        );
+    }
+
+    #[test]
+    fn from_str_rejects_invalid_thresholds() {
+        for input in &[
+            "", "abc", "%", ".%", "100.5%", "-1%", "1e2%", "abc%", "free", "x free",
+        ] {
+            assert!(
+                Threshold::from_str(input).is_err(),
+                "{:?} should be invalid",
+                input,
+            );
+        }
+    }
+
+    #[test]
+    fn display_round_trips() {
+        for input in &["80%", "12.5%", "0.01%", "100%"] {
+            assert_eq!(Threshold::from_str(input).unwrap().to_string(), *input);
+        }
+        assert_eq!(
+            Threshold::from_str("20 GB free").unwrap().to_string(),
+            "20.00 GB free",
+        );
+    }
+
+    #[test]
+    fn resolve_absolute_ignores_the_filesystem() {
+        let mut backend = FakeBackend::new(&[]);
+        backend.root_dir = env::temp_dir().join("docuum-missing-root");
+        let threshold = Byte::from_bytes(10_000_000_000);
+        assert_eq!(
+            Threshold::Absolute(threshold)
+                .resolve(&backend, Byte::from_bytes(0))
+                .unwrap(),
+            threshold,
+        );
+    }
+
+    #[test]
+    fn resolve_percentage_of_capacity() {
+        let backend = FakeBackend::new(&[]);
+        let capacity = fs2::total_space(env::temp_dir()).unwrap();
+        assert_eq!(
+            Threshold::Percentage(2500)
+                .resolve(&backend, Byte::from_bytes(0))
+                .unwrap(),
+            Byte::from_bytes(u128::from(capacity) / 4),
+        );
+    }
+
+    #[test]
+    fn resolve_reports_a_missing_root_directory() {
+        let mut backend = FakeBackend::new(&[]);
+        backend.root_dir = env::temp_dir().join("docuum-missing-root");
+        for threshold in &[
+            Threshold::Percentage(8000),
+            Threshold::Free(Byte::from_bytes(1)),
+        ] {
+            let error = threshold
+                .resolve(&backend, Byte::from_bytes(0))
+                .unwrap_err();
+            assert!(error.to_string().contains("mount that directory"));
+        }
+    }
+}
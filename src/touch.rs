@@ -0,0 +1,35 @@
+use crate::{
+    run::{now, update_timestamp},
+    state, Settings,
+};
+use std::io;
+
+// Mark images as used now, e.g., because they're used in ways the container runtime doesn't report.
+// A running daemon picks up the change the next time it vacuums.
+pub fn touch(settings: &Settings, images: &[&str]) -> io::Result<()> {
+    // Resolve every image before changing anything, so a typo doesn't result in a partial update.
+    let backend = settings.backend.connect()?;
+    let image_ids = images
+        .iter()
+        .map(|image| backend.image_id(image))
+        .collect::<io::Result<Vec<_>>>()?;
+
+    // Keep the daemon from saving the state while we update it.
+    let mut store = settings.state_format.open(settings.state_path.as_deref())?;
+    let _lock = state::lock(store.path())?;
+
+    // Load the state. It's fine if there isn't any yet, but a state which can't be read is left alone
+    // rather than overwritten.
+    let mut state = match state::load(&mut *store) {
+        Ok(state) => state,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => state::initial(),
+        Err(error) => return Err(error),
+    };
+
+    let now = now()?;
+    for image_id in image_ids {
+        update_timestamp(&mut state, &image_id, now, "touch", true).use_count += 1;
+    }
+
+    store.save(&state)
+}
@@ -0,0 +1,282 @@
+use crate::{
+    backend::{Backend, DiskUsage, Volume},
+    duration,
+    format::{timestamp, CodeStr},
+    state::State,
+    Settings,
+};
+use byte_unit::Byte;
+use std::{collections::HashSet, io, time::Duration};
+
+// Determine whether a volume policy is configured. Volumes are only deleted if so.
+pub fn has_policy(settings: &Settings) -> bool {
+    settings.volume_threshold.is_some() || settings.volume_max_idle.is_some()
+}
+
+// Determine when a volume was last used. The `unwrap` is safe as long as every volume has a record
+// in `state`, which `record_uses` ensures.
+fn last_used(state: &State, volume: &Volume) -> Duration {
+    *state.volumes.get(&volume.name).unwrap()
+}
+
+// Determine whether a volume has been idle for too long.
+fn expired(state: &State, settings: &Settings, volume: &Volume, now: Duration) -> bool {
+    settings
+        .volume_max_idle
+        .is_some_and(|max_idle| now.saturating_sub(last_used(state, volume)) > max_idle)
+}
+
+// Make sure `state` has a record for every volume and nothing else, and update the timestamps of
+// any volumes in use.
+fn record_uses(state: &mut State, usage: &DiskUsage, now: Duration) {
+    // Remove non-existent volumes from `state`.
+    let names = usage
+        .volumes
+        .iter()
+        .map(|volume| volume.name.as_str())
+        .collect::<HashSet<_>>();
+    state.volumes.retain(|name, _| {
+        if names.contains(name.as_str()) {
+            true
+        } else {
+            debug!(
+                "Removing record for non-existent volume {}\u{2026}",
+                name.code_str(),
+            );
+            false
+        }
+    });
+
+    // Add any missing volumes to `state`, and update the timestamps of any volumes in use.
+    for volume in &usage.volumes {
+        if volume.containers > 0 {
+            state.volumes.insert(volume.name.clone(), now);
+        } else {
+            state.volumes.entry(volume.name.clone()).or_insert_with(|| {
+                debug!(
+                    "Adding missing record for volume {}\u{2026}",
+                    volume.name.code_str(),
+                );
+
+                now
+            });
+        }
+    }
+}
+
+// Delete the planned volumes, along with how much space volumes are projected to use after each
+// deletion, or just report the plan if this is a dry run.
+fn carry_out_evictions(
+    backend: &dyn Backend,
+    state: &State,
+    settings: &Settings,
+    evictions: &[(&Volume, u128)],
+    now: Duration,
+) {
+    for (volume, projected_space) in evictions {
+        if settings.dry_run {
+            info!(
+                "Would delete volume {}, last used at {}, which would free {}. {} volumes would \
+                 then use about {}.",
+                volume.name.code_str(),
+                timestamp(last_used(state, volume)).code_str(),
+                volume
+                    .size
+                    .get_appropriate_unit(false)
+                    .to_string()
+                    .code_str(),
+                backend.name(),
+                Byte::from_bytes(*projected_space)
+                    .get_appropriate_unit(false)
+                    .to_string()
+                    .code_str(),
+            );
+        } else {
+            if expired(state, settings, volume, now) {
+                // The `unwrap` is safe because only expired volumes get here.
+                info!(
+                    "Volume {} has been idle for longer than {}.",
+                    volume.name.code_str(),
+                    duration::format(settings.volume_max_idle.unwrap()).code_str(),
+                );
+            }
+
+            info!("Deleting volume {}\u{2026}", volume.name.code_str());
+            if let Err(error) = backend.delete_volume(&volume.name) {
+                error!("{}", error);
+            }
+        }
+    }
+}
+
+// Record when each volume was last used. Then, if a volume policy is configured, delete dangling
+// anonymous volumes which have been idle for too long, followed by the least recently used ones if
+// volumes are over their threshold. Returns when the next volume will have been idle for too long,
+// if ever, as a duration since the UNIX epoch.
+pub fn vacuum(
+    backend: &dyn Backend,
+    state: &mut State,
+    settings: &Settings,
+    usage: &DiskUsage,
+    now: Duration,
+) -> io::Result<Option<Duration>> {
+    record_uses(state, usage, now);
+
+    // Stop here unless there's a volume policy.
+    if !has_policy(settings) {
+        return Ok(None);
+    }
+
+    // Check if we're over the volume threshold, if there is one.
+    let space = Byte::from_bytes(
+        usage
+            .volumes
+            .iter()
+            .map(|volume| volume.size.get_bytes())
+            .sum(),
+    );
+    let threshold = settings
+        .volume_threshold
+        .map(|threshold| threshold.resolve(backend, space))
+        .transpose()?;
+    if let Some(threshold) = threshold {
+        info!(
+            "{} volumes are using {} and the limit is {}.",
+            backend.name(),
+            space.get_appropriate_unit(false).to_string().code_str(),
+            threshold.get_appropriate_unit(false).to_string().code_str(),
+        );
+    }
+
+    // Only dangling anonymous volumes are candidates for deletion, least recently used first.
+    let mut candidates = usage
+        .volumes
+        .iter()
+        .filter(|volume| volume.containers == 0 && volume.anonymous)
+        .collect::<Vec<_>>();
+    candidates.sort_by_key(|volume| last_used(state, volume));
+
+    // Delete every expired candidate, and keep going until we're within the threshold. Since all
+    // candidates have the same maximum idle time, the expired ones come first.
+    let mut projected_space = space.get_bytes();
+    let mut evictions = vec![];
+    for volume in candidates.iter().copied() {
+        if !expired(state, settings, volume, now)
+            && threshold.map_or(true, |threshold| projected_space <= threshold.get_bytes())
+        {
+            break;
+        }
+
+        projected_space = projected_space.saturating_sub(volume.size.get_bytes());
+        evictions.push((volume, projected_space));
+    }
+
+    // Report the plan if this is a dry run. Otherwise, carry it out.
+    carry_out_evictions(backend, state, settings, &evictions, now);
+
+    // Determine when the next remaining candidate will have been idle for too long.
+    Ok(settings.volume_max_idle.and_then(|max_idle| {
+        candidates
+            .iter()
+            .skip(evictions.len())
+            .map(|volume| last_used(state, volume) + max_idle)
+            .min()
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::vacuum;
+    use crate::{
+        backend::{fake::FakeBackend, Backend},
+        fixtures::{settings, state},
+        threshold::Threshold,
+    };
+    use byte_unit::Byte;
+    use std::time::Duration;
+
+    // A name of the kind Docker gives anonymous volumes
+    fn anonymous(n: u8) -> String {
+        format!("{:064x}", n)
+    }
+
+    #[test]
+    fn idle_anonymous_volume_is_deleted() {
+        let backend = FakeBackend::new(&[]);
+        backend.add_volume(&anonymous(1), 100, 0);
+        backend.add_volume(&anonymous(2), 100, 0);
+        let mut state = state(&[], &[(&anonymous(1), 10), (&anonymous(2), 95)]);
+        let mut settings = settings(0);
+        settings.volume_max_idle = Some(Duration::from_secs(10));
+
+        let next_expiry = vacuum(
+            &backend,
+            &mut state,
+            &settings,
+            &backend.disk_usage().unwrap(),
+            Duration::from_secs(100),
+        )
+        .unwrap();
+        assert_eq!(backend.deleted(), vec![anonymous(1)]);
+        assert_eq!(next_expiry, Some(Duration::from_secs(105)));
+    }
+
+    #[test]
+    fn named_volumes_are_kept() {
+        let backend = FakeBackend::new(&[]);
+        backend.add_volume("data", 100, 0);
+        backend.add_volume(&anonymous(1), 100, 0);
+        let mut state = state(&[], &[("data", 10), (&anonymous(1), 10)]);
+
+        // Without a policy, not even anonymous volumes are deleted.
+        let mut settings = settings(0);
+        vacuum(
+            &backend,
+            &mut state,
+            &settings,
+            &backend.disk_usage().unwrap(),
+            Duration::from_secs(100),
+        )
+        .unwrap();
+        assert!(backend.deleted().is_empty());
+
+        // With a policy, only the anonymous volume is deleted.
+        settings.volume_max_idle = Some(Duration::from_secs(10));
+        vacuum(
+            &backend,
+            &mut state,
+            &settings,
+            &backend.disk_usage().unwrap(),
+            Duration::from_secs(100),
+        )
+        .unwrap();
+        assert_eq!(backend.deleted(), vec![anonymous(1)]);
+    }
+
+    #[test]
+    fn volumes_in_use_are_never_deleted() {
+        let backend = FakeBackend::new(&[]);
+        backend.add_volume(&anonymous(1), 1000, 1);
+        backend.add_volume(&anonymous(2), 10, 0);
+        let mut state = state(&[], &[(&anonymous(1), 10), (&anonymous(2), 50)]);
+        let mut settings = settings(0);
+        settings.volume_threshold = Some(Threshold::Absolute(Byte::from_bytes(0)));
+        settings.volume_max_idle = Some(Duration::from_secs(10));
+
+        vacuum(
+            &backend,
+            &mut state,
+            &settings,
+            &backend.disk_usage().unwrap(),
+            Duration::from_secs(100),
+        )
+        .unwrap();
+        assert_eq!(backend.deleted(), vec![anonymous(2)]);
+
+        // Using the volume counts as a use of it.
+        assert_eq!(
+            state.volumes.get(&anonymous(1)),
+            Some(&Duration::from_secs(100)),
+        );
+    }
+}